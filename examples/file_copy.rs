@@ -1,19 +1,16 @@
 use omnistreams::{Producer, ReadAdapter, WriteAdapter};
-use futures::future::lazy;
 
 
-fn main() {
+#[tokio::main]
+async fn main() {
 
-    tokio::run(lazy(|| {
+    let file_reader = tokio::fs::File::open("in.txt");
+    let producer = ReadAdapter::new(file_reader);
 
-        let file_reader = tokio::fs::File::open("in.txt");
-        let producer = ReadAdapter::new(file_reader);
+    let file_writer = tokio::fs::File::create("out.txt");
+    let consumer = WriteAdapter::new(file_writer);
 
-        let file_writer = tokio::fs::File::create("out.txt");
-        let consumer = WriteAdapter::new(file_writer);
-
-        producer.pipe(consumer);
-
-        Ok(())
-    }));
+    if let Err(e) = producer.pipe_into(consumer).await {
+        eprintln!("Copy failed: {}", e);
+    }
 }
@@ -1,26 +1,20 @@
-use omnistreams::{Producer, RangeProducerBuilder, ProducerEvent};
+use omnistreams::{Producer, RangeProducerBuilder};
+use futures::StreamExt;
 
 
 fn main() {
 
-    omnistreams::runtime::run(|| {
+    omnistreams::runtime::run(async {
 
-        let mut producer = RangeProducerBuilder::new()
+        let producer = RangeProducerBuilder::new()
             .start(5)
             .stop(10)
             .build();
 
-        producer.request(1);
+        let mut values = producer.into_stream(1);
 
-        producer.events().for_each(move |event| {
-            match event {
-                ProducerEvent::Data(value) => {
-                    println!("{}", value);
-                    producer.request(1);
-                },
-                _ => {
-                }
-            }
-        });
+        while let Some(value) = values.next().await {
+            println!("{}", value);
+        }
     });
 }
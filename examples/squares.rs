@@ -1,9 +1,10 @@
-use omnistreams::{Producer, RangeProducerBuilder, MapConduit, ProducerEvent};
+use omnistreams::{Producer, RangeProducerBuilder, MapConduit};
+use futures::StreamExt;
 
 
 fn main() {
 
-    omnistreams::runtime::run(|| {
+    omnistreams::runtime::run(async {
 
         let producer = RangeProducerBuilder::new()
             .start(5)
@@ -11,20 +12,11 @@ fn main() {
             .build();
         let conduit = MapConduit::new(|x| x*x);
 
-        let mut square_producer = producer
+        let (square_producer, _) = producer
             .pipe_through(conduit);
 
-        square_producer.request(1);
-
-        square_producer.events().for_each(move |event| {
-            match event {
-                ProducerEvent::Data(value) => {
-                    println!("{}", value);
-                    square_producer.request(1);
-                },
-                _ => {
-                }
-            }
-        });
+        square_producer.into_stream(4).for_each(|value| async move {
+            println!("{}", value);
+        }).await;
     });
 }
@@ -1,28 +1,28 @@
 use omnistreams::{
     Producer, RangeProducerBuilder, MapConduit, WriteAdapter,
 };
-use futures::future::lazy;
 
 
-fn main() {
+#[tokio::main]
+async fn main() {
 
-    tokio::run(lazy(|| {
+    let file_writer = tokio::fs::File::create("squares.txt");
 
-        let file_writer = tokio::fs::File::create("squares.txt");
+    let producer = RangeProducerBuilder::new()
+        .start(0)
+        .stop(100)
+        .build();
 
-        let mut producer = RangeProducerBuilder::new()
-            .start(0)
-            .stop(100)
-            .build();
+    let (squares, _) = producer
+        .pipe_through(MapConduit::new(|x| x*x));
+    let (lines, _) = squares
+        .pipe_through(MapConduit::new(|x| format!("{:?}\n", x).as_bytes().to_vec()));
 
-        producer.request(200);
+    //let result = lines.pipe_into(WriteAdapter::new(futures::future::ok(tokio::io::stdout()))).await;
+    let result = lines.pipe_into(WriteAdapter::new(file_writer)).await;
 
-        producer
-            .pipe_conduit(MapConduit::new(|x| x*x))
-            .pipe_conduit(MapConduit::new(|x| format!("{:?}\n", x).as_bytes().to_vec()))
-            //.pipe(WriteAdapter::new(futures::future::ok(tokio::io::stdout())));
-            .pipe(WriteAdapter::new(file_writer));
-            
-        Ok(())
-    }));
+    match result {
+        Ok(stats) => println!("Wrote {} lines in {:?}", stats.items, stats.duration),
+        Err(e) => eprintln!("Failed: {}", e),
+    }
 }
@@ -1,27 +1,30 @@
-use omnistreams::{ReadAdapter, WriteAdapter, pipe};
+use omnistreams::{Producer, ReadAdapter, WriteAdapter};
 use tokio::net::TcpListener;
-use tokio::prelude::*;
-use std::net::SocketAddr;
 
 
 
-fn main() {
-    
-    let addr = "127.0.0.1:9001".parse::<SocketAddr>().unwrap();
-    let listener = TcpListener::bind(&addr).unwrap();
+#[tokio::main]
+async fn main() {
 
-    tokio::run(listener.incoming()
-        .map_err(|e| eprintln!("failed to accept socket; error = {:?}", e))
-        .for_each(|socket| {
-            println!("New session:");
+    let listener = TcpListener::bind("127.0.0.1:9001").await.unwrap();
 
-            let tcp_reader = futures::future::ok(socket);
-            let producer = ReadAdapter::new(tcp_reader);
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _addr)) => socket,
+            Err(e) => {
+                eprintln!("failed to accept socket; error = {:?}", e);
+                continue;
+            },
+        };
 
-            let stdout_writer = futures::future::ok(tokio::io::stdout());
-            let consumer = WriteAdapter::new(stdout_writer);
+        println!("New session:");
 
-            pipe(producer, consumer);
-            Ok(())
-        }));
+        let tcp_reader = futures::future::ok(socket);
+        let producer = ReadAdapter::new(tcp_reader);
+
+        let stdout_writer = futures::future::ok(tokio::io::stdout());
+        let consumer = WriteAdapter::new(stdout_writer);
+
+        producer.pipe_into(consumer);
+    }
 }
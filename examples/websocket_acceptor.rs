@@ -1,23 +1,18 @@
 use omnistreams::{Acceptor, WebSocketAcceptorBuilder};
-use futures::future::lazy;
-use tokio::prelude::*;
+use futures::StreamExt;
 
 
-fn main() {
-    tokio::run(lazy(|| {
-        let mut acceptor = WebSocketAcceptorBuilder::new()
-            .port(9001)
-            .build();
+#[tokio::main]
+async fn main() {
+    let mut acceptor = WebSocketAcceptorBuilder::new()
+        .port(9001)
+        .build()
+        .await
+        .expect("bind acceptor");
 
-        let transports = acceptor.transports().expect("no transports");
+    let mut transports = acceptor.transports().expect("no transports");
 
-        tokio::spawn(transports.for_each(|transport| {
-            Ok(())
-        })
-        .map_err(|e| {
-            eprintln!("{:?}", e);
-        }));
-
-        Ok(())
-    }));
+    while let Some(_transport) = transports.next().await {
+        println!("Got a transport");
+    }
 }
@@ -2,48 +2,36 @@ use omnistreams::{
     Producer, Transport, EventEmitter, Acceptor, WebSocketAcceptorBuilder,
     Multiplexer, MultiplexerEvent, WriteAdapter 
 };
-use futures::future::lazy;
-use tokio::prelude::*;
+use futures::StreamExt;
 
 
-fn main() {
-    tokio::run(lazy(|| {
-        let mut acceptor = WebSocketAcceptorBuilder::new()
-            .port(9001)
-            .build();
+#[tokio::main]
+async fn main() {
+    let mut acceptor = WebSocketAcceptorBuilder::new()
+        .port(9001)
+        .build()
+        .await
+        .expect("bind acceptor");
 
-        let transports = acceptor.transports().expect("no transports");
+    let mut transports = acceptor.transports().expect("no transports");
 
-        tokio::spawn(transports.for_each(|transport| {
-            handle_transport(transport); 
-            Ok(())
-        })
-        .map_err(|e| {
-            eprintln!("{:?}", e);
-        }));
-
-        Ok(())
-    }));
+    while let Some(transport) = transports.next().await {
+        handle_transport(transport); 
+    }
 }
 
 fn handle_transport<T: Transport + Send + 'static>(transport: T) {
     let mut mux = Multiplexer::new(transport);
 
-    let events = mux.events().unwrap();
+    let mut events = mux.events().unwrap();
 
-    tokio::spawn(events.for_each(|event| {
-        match event {
-            MultiplexerEvent::Conduit(producer) => {
+    tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            if let MultiplexerEvent::Conduit(producer, _metadata) = event {
                 let file_writer = tokio::fs::File::create("outfile");
                 producer
-                    .pipe(WriteAdapter::new(file_writer));
-            },
-            _ => {
-            },
+                    .pipe_into(WriteAdapter::new(file_writer));
+            }
         }
-        Ok(())
-    })
-    .map_err(|e| {
-        eprintln!("{:?}", e);
-    }));
+    });
 }
@@ -1,5 +1,9 @@
-use futures::sync::mpsc;
-use super::CancelReason;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc;
+use futures::{Sink, StreamExt};
+use super::{CancelReason, Error};
 
 
 pub type ConsumerMessageRx<T> = mpsc::UnboundedReceiver<ConsumerMessage<T>>;
@@ -13,6 +17,7 @@ pub type ConsumerEventTx = mpsc::UnboundedSender<ConsumerEvent>;
 pub enum ConsumerMessage<T> {
     Write(T),
     End,
+    Abort(Error),
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -23,8 +28,182 @@ pub enum ConsumerEvent {
 pub trait Consumer<T> {
     fn write(&self, data: T);
     fn end(&self);
+    /// End the stream because something went wrong upstream. Unlike `end`,
+    /// whatever was written so far shouldn't be taken as complete.
+    fn abort(&self, error: Error);
     fn event_stream(&mut self) -> Option<ConsumerEventRx>;
     fn set_event_stream(&mut self, event_stream: ConsumerEventRx);
+
+    /// Use the consumer as a `Sink`. It's only ready while the consumer has
+    /// requested more than it was sent, closing it ends the consumer, and a
+    /// cancellation fails it with `Error::Cancelled`.
+    fn into_sink(mut self) -> ConsumerSink<T, Self>
+        where Self: Sized,
+    {
+        let event_rx = self.event_stream().expect("event stream");
+
+        ConsumerSink {
+            item_type: PhantomData,
+            consumer: self,
+            event_rx,
+            demand: 0,
+            cancelled: None,
+            events_done: false,
+            closed: false,
+        }
+    }
+}
+
+pub struct ConsumerSink<T, C>
+    where C: Consumer<T>,
+{
+    item_type: PhantomData<T>,
+    consumer: C,
+    event_rx: ConsumerEventRx,
+    demand: usize,
+    cancelled: Option<CancelReason>,
+    // the consumer is gone, so no more demand is coming
+    events_done: bool,
+    closed: bool,
+}
+
+impl<T, C> ConsumerSink<T, C>
+    where C: Consumer<T>,
+{
+    fn process_events(&mut self, cx: &mut Context) {
+        while let Poll::Ready(event) = self.event_rx.poll_next_unpin(cx) {
+            match event {
+                Some(ConsumerEvent::Request(num_items)) => {
+                    self.demand += num_items;
+                },
+                Some(ConsumerEvent::Cancellation(reason)) => {
+                    self.cancelled = Some(reason);
+                },
+                None => {
+                    self.events_done = true;
+                    break;
+                },
+            }
+        }
+    }
+
+    fn check_cancelled(&self) -> Result<(), Error> {
+        match self.cancelled {
+            Some(ref reason) => Err(Error::Cancelled(reason.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+// Neither the consumer nor the event channel are ever pinned.
+impl<T, C> Unpin for ConsumerSink<T, C>
+    where C: Consumer<T>,
+{}
+
+impl<T, C> Sink<T> for ConsumerSink<T, C>
+    where C: Consumer<T>,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+
+        this.process_events(cx);
+        this.check_cancelled()?;
+
+        if this.demand > 0 {
+            Poll::Ready(Ok(()))
+        }
+        else if this.events_done {
+            Poll::Ready(Err(Error::Disconnected))
+        }
+        else {
+            Poll::Pending
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Error> {
+        let this = self.get_mut();
+
+        this.check_cancelled()?;
+
+        if this.demand == 0 {
+            panic!("ConsumerSink: Attempt to send without poll_ready");
+        }
+
+        this.demand -= 1;
+        this.consumer.write(item);
+        Ok(())
+    }
+
+    // Items go straight to the consumer, so there's nothing to flush beyond
+    // noticing a cancellation.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+
+        this.process_events(cx);
+        Poll::Ready(this.check_cancelled())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+
+        this.process_events(cx);
+        this.check_cancelled()?;
+
+        if !this.closed {
+            this.closed = true;
+            this.consumer.end();
+        }
+
+        Poll::Ready(Ok(()))
+    }
 }
 
 
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use futures::{FutureExt, SinkExt, stream};
+    use crate::{MapConduit, Conduit, Producer, Streamer};
+
+    #[tokio::test]
+    async fn send_all() {
+        let (consumer, producer) = MapConduit::new(|x: i32| x + 1).split();
+
+        let received = tokio::spawn(producer.into_stream(2).collect::<Vec<i32>>());
+
+        let mut sink = consumer.into_sink();
+        sink.send_all(&mut stream::iter(vec![Ok(1), Ok(2), Ok(3)])).await.unwrap();
+        sink.close().await.unwrap();
+
+        assert_eq!(received.await.unwrap(), vec![2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn ready_only_with_demand() {
+        let (consumer, mut producer) = MapConduit::new(|x: i32| x).split();
+
+        let mut sink = consumer.into_sink();
+        assert!(futures::future::poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx)).now_or_never().is_none());
+
+        producer.request(1);
+        sink.send(5).await.unwrap();
+        assert!(futures::future::poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx)).now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn cancellation_is_an_error() {
+        let (consumer, mut producer) = MapConduit::new(|x: i32| x).split();
+
+        let mut sink = consumer.into_sink();
+
+        producer.cancel(CancelReason::Other("enough".to_string()));
+
+        match sink.send(1).await {
+            Err(Error::Cancelled(CancelReason::Other(reason))) => assert_eq!(reason, "enough"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+use std::io;
+use super::{CancelReason, ProtocolError};
+
+
+/// Why a stream was aborted instead of ending cleanly.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Protocol(ProtocolError),
+    Cancelled(CancelReason),
+    Disconnected,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Protocol(e) => write!(f, "protocol error: {}", e),
+            Error::Cancelled(CancelReason::Disconnected) => write!(f, "cancelled"),
+            Error::Cancelled(CancelReason::Other(reason)) => write!(f, "cancelled: {}", reason),
+            Error::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+// io::Error can't be cloned, so the copy only keeps its kind and message.
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        match self {
+            Error::Io(e) => Error::Io(io::Error::new(e.kind(), e.to_string())),
+            Error::Protocol(e) => Error::Protocol(e.clone()),
+            Error::Cancelled(reason) => Error::Cancelled(reason.clone()),
+            Error::Disconnected => Error::Disconnected,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Error {
+        Error::Protocol(e)
+    }
+}
@@ -0,0 +1,100 @@
+use super::{MapConsumer, MapProducer};
+use super::map_conduit::{spawn_conduit, forward_to_halves};
+
+
+/// Passes on only the items `predicate` accepts. Every item it drops is
+/// requested again upstream, so downstream still gets as many items as it
+/// asked for.
+#[derive(Debug)]
+pub struct FilterConduit<T> {
+    consumer: MapConsumer<T>,
+    producer: MapProducer<T>,
+}
+
+/// Maps items like `MapConduit`, dropping the ones `f` maps to `None`. Like
+/// `FilterConduit`, dropped items are requested again upstream.
+#[derive(Debug)]
+pub struct FilterMapConduit<A, B> {
+    consumer: MapConsumer<A>,
+    producer: MapProducer<B>,
+}
+
+impl<T> FilterConduit<T>
+    where T: Send + 'static,
+{
+    pub fn new<F: FnMut(&T) -> bool + Send + 'static>(mut predicate: F) -> FilterConduit<T> {
+        let (consumer, producer) = spawn_conduit(move |data| {
+            if predicate(&data) {
+                Some(data)
+            }
+            else {
+                None
+            }
+        });
+
+        FilterConduit {
+            consumer,
+            producer,
+        }
+    }
+}
+
+impl<A, B> FilterMapConduit<A, B>
+    where A: Send + 'static,
+          B: Send + 'static,
+{
+    pub fn new<F: FnMut(A) -> Option<B> + Send + 'static>(f: F) -> FilterMapConduit<A, B> {
+        let (consumer, producer) = spawn_conduit(f);
+
+        FilterMapConduit {
+            consumer,
+            producer,
+        }
+    }
+}
+
+forward_to_halves!(FilterConduit<T>, T, T);
+forward_to_halves!(FilterMapConduit<A, B>, A, B);
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use futures::StreamExt;
+    use crate::{RangeProducer, StreamProducer, Consumer, Producer, ConsumerEvent};
+
+    #[tokio::test]
+    async fn filter_does_not_stall() {
+        let producer = RangeProducer::new(0, Some(20));
+
+        let (evens, _) = producer.pipe_through(FilterConduit::new(|x: &i64| x % 2 == 0));
+
+        let items: Vec<i64> = evens.into_stream(1).collect().await;
+        assert_eq!(items, (0..20).step_by(2).collect::<Vec<i64>>());
+    }
+
+    #[tokio::test]
+    async fn dropped_items_are_replaced() {
+        let mut conduit = FilterConduit::new(|x: &i32| *x > 0);
+
+        let mut consumer_events = Consumer::event_stream(&mut conduit).unwrap();
+
+        conduit.request(2);
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(2)));
+
+        conduit.write(-1);
+        conduit.write(1);
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(1)));
+    }
+
+    #[tokio::test]
+    async fn filter_map() {
+        let words = StreamProducer::new(futures::stream::iter(vec!["1", "two", "3", "", "5"]));
+
+        let (numbers, _) = words.pipe_through(FilterMapConduit::new(|s: &str| s.parse::<i32>().ok()));
+
+        let items: Vec<i32> = numbers.into_stream(2).collect().await;
+        assert_eq!(items, vec![1, 3, 5]);
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc;
+use futures::{stream, StreamExt};
+use super::{
+    Producer, ProducerEvent, ProducerEventRx, ProducerEventTx,
+    ProducerMessage, ProducerMessageRx, ProducerMessageTx,
+    Streamer, CancelReason, Error, StreamProducer,
+};
+
+
+/// Plays the producers emitted by an outer producer. Inner producers are
+/// started as soon as the outer one has them, up to the concurrency limit,
+/// and those that run at the same time have their items interleaved, so use
+/// a concurrency of 1 to keep them in order. Demand an inner producer didn't fulfil
+/// before ending carries over to the next one.
+#[derive(Debug)]
+pub struct Flatten<T> {
+    message_tx: ProducerMessageTx,
+    event_rx: Option<ProducerEventRx<T>>,
+}
+
+pub struct FlattenBuilder {
+    concurrency: Option<usize>,
+}
+
+/// Plays a fixed list of producers one after the other.
+#[derive(Debug)]
+pub struct Concat<T> {
+    flatten: Flatten<T>,
+}
+
+struct Inner<T, P> {
+    producer: P,
+    event_rx: ProducerEventRx<T>,
+    // requested but not received yet
+    in_flight: usize,
+    ended: bool,
+}
+
+struct InnerTask<T, O, P> {
+    message_rx: ProducerMessageRx,
+    event_tx: ProducerEventTx<T>,
+    outer: O,
+    outer_rx: ProducerEventRx<P>,
+    outer_requested: bool,
+    outer_ended: bool,
+    inners: Vec<Inner<T, P>>,
+    // received from inner producers but not sent downstream yet
+    buffer: VecDeque<T>,
+    // index of the inner producer first in line for what doesn't split evenly
+    next: usize,
+    demand: usize,
+    concurrency: Option<usize>,
+}
+
+impl Default for FlattenBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlattenBuilder {
+    pub fn new() -> FlattenBuilder {
+        FlattenBuilder {
+            concurrency: None,
+        }
+    }
+
+    /// Most inner producers to run at once. Unlimited by default.
+    pub fn concurrency(mut self, value: usize) -> FlattenBuilder {
+        assert!(value > 0, "concurrency must be at least 1");
+        self.concurrency = Some(value);
+        self
+    }
+
+    pub fn build<T, O, P>(self, mut outer: O) -> Flatten<T>
+        where T: Send + 'static,
+              O: Producer<P> + Send + 'static,
+              P: Producer<T> + Send + 'static,
+    {
+        let (message_tx, message_rx) = mpsc::unbounded::<ProducerMessage>();
+        let (event_tx, event_rx) = mpsc::unbounded::<ProducerEvent<T>>();
+
+        let outer_rx = outer.event_stream().expect("no event stream");
+
+        let inner_task = InnerTask {
+            message_rx,
+            event_tx,
+            outer,
+            outer_rx,
+            outer_requested: false,
+            outer_ended: false,
+            inners: Vec::new(),
+            buffer: VecDeque::new(),
+            next: 0,
+            demand: 0,
+            concurrency: self.concurrency,
+        };
+        tokio::spawn(inner_task);
+
+        Flatten {
+            message_tx,
+            event_rx: Some(event_rx),
+        }
+    }
+}
+
+impl<T> Flatten<T>
+    where T: Send + 'static,
+{
+    pub fn new<O, P>(outer: O) -> Flatten<T>
+        where O: Producer<P> + Send + 'static,
+              P: Producer<T> + Send + 'static,
+    {
+        FlattenBuilder::new().build(outer)
+    }
+}
+
+impl<T> Streamer for Flatten<T> {
+    fn cancel(&mut self, reason: CancelReason) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Cancel(reason));
+    }
+}
+
+impl<T> Producer<T> for Flatten<T>
+    where T: Send + 'static,
+{
+    fn request(&mut self, num_items: usize) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Request(num_items));
+    }
+
+    fn event_stream(&mut self) -> Option<ProducerEventRx<T>> {
+        Option::take(&mut self.event_rx)
+    }
+
+    fn set_event_stream(&mut self, event_stream: ProducerEventRx<T>) {
+        self.event_rx = Some(event_stream);
+    }
+}
+
+impl<T> Concat<T>
+    where T: Send + 'static,
+{
+    pub fn new<I, P>(producers: I) -> Concat<T>
+        where I: IntoIterator<Item=P>,
+              P: Producer<T> + Send + 'static,
+    {
+        let producers: Vec<P> = producers.into_iter().collect();
+        let outer = StreamProducer::new(stream::iter(producers));
+
+        Concat {
+            flatten: FlattenBuilder::new().concurrency(1).build(outer),
+        }
+    }
+}
+
+impl<T> Streamer for Concat<T> {
+    fn cancel(&mut self, reason: CancelReason) {
+        self.flatten.cancel(reason);
+    }
+}
+
+impl<T> Producer<T> for Concat<T>
+    where T: Send + 'static,
+{
+    fn request(&mut self, num_items: usize) {
+        self.flatten.request(num_items);
+    }
+
+    fn event_stream(&mut self) -> Option<ProducerEventRx<T>> {
+        self.flatten.event_stream()
+    }
+
+    fn set_event_stream(&mut self, event_stream: ProducerEventRx<T>) {
+        self.flatten.set_event_stream(event_stream);
+    }
+}
+
+impl<T, O, P> InnerTask<T, O, P>
+    where T: Send + 'static,
+          O: Producer<P>,
+          P: Producer<T> + Send + 'static,
+{
+    fn cancel_all(&mut self, reason: CancelReason) {
+        if !self.outer_ended {
+            self.outer.cancel(reason.clone());
+        }
+
+        for inner in self.inners.iter_mut() {
+            if !inner.ended {
+                inner.producer.cancel(reason.clone());
+            }
+        }
+    }
+
+    fn fail(&mut self, e: Error) -> Poll<()> {
+        self.cancel_all(CancelReason::Other(e.to_string()));
+        // Consumer may already be gone, in which case there's nobody to tell
+        let _ = self.event_tx.unbounded_send(ProducerEvent::Error(e));
+        Poll::Ready(())
+    }
+
+    // demand that nothing has been received for yet
+    fn unfulfilled(&self) -> usize {
+        self.demand.saturating_sub(self.buffer.len())
+    }
+
+    // Split the demand nothing has been received for yet evenly between the
+    // inner producers, with what doesn't split evenly going to them in turn.
+    // Each one gets at least one item though, so an idle inner producer
+    // can't hold up the rest.
+    fn request_shares(&mut self) {
+        let unfulfilled = self.unfulfilled();
+        let num_inners = self.inners.len();
+
+        if unfulfilled == 0 || num_inners == 0 {
+            return;
+        }
+
+        let share = unfulfilled / num_inners;
+        let extra = unfulfilled % num_inners;
+
+        for turn in 0..num_inners {
+            let target = if turn < extra { share + 1 } else { share.max(1) };
+            let inner = &mut self.inners[(self.next + turn) % num_inners];
+
+            if target > inner.in_flight {
+                inner.producer.request(target - inner.in_flight);
+                inner.in_flight = target;
+            }
+        }
+
+        self.next = (self.next + extra) % num_inners;
+    }
+
+    fn request_outer(&mut self) {
+        let below_limit = match self.concurrency {
+            Some(limit) => self.inners.len() < limit,
+            None => true,
+        };
+
+        // Started whether or not anything is wanted yet, so one that's idle
+        // can't keep the others from running.
+        if !self.outer_ended && !self.outer_requested && below_limit {
+            self.outer.request(1);
+            self.outer_requested = true;
+        }
+    }
+}
+
+// The producers and channels are never pinned.
+impl<T, O, P> Unpin for InnerTask<T, O, P> {}
+
+impl<T, O, P> Future for InnerTask<T, O, P>
+    where T: Send + 'static,
+          O: Producer<P>,
+          P: Producer<T> + Send + 'static,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+
+        let this = self.get_mut();
+
+        loop {
+            match this.message_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ProducerMessage::Request(num_items))) => {
+                    this.demand += num_items;
+                },
+                Poll::Ready(Some(ProducerMessage::Cancel(reason))) => {
+                    this.cancel_all(reason);
+                    return Poll::Ready(());
+                },
+                Poll::Ready(None) => {
+                    this.cancel_all(CancelReason::Disconnected);
+                    return Poll::Ready(());
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
+
+        while !this.outer_ended {
+            match this.outer_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ProducerEvent::Data(mut producer))) => {
+                    this.outer_requested = false;
+
+                    let event_rx = producer.event_stream().expect("no event stream");
+
+                    this.inners.push(Inner {
+                        producer,
+                        event_rx,
+                        in_flight: 0,
+                        ended: false,
+                    });
+                },
+                Poll::Ready(Some(ProducerEvent::End)) => {
+                    this.outer_ended = true;
+                },
+                Poll::Ready(Some(ProducerEvent::Error(e))) => {
+                    this.outer_ended = true;
+                    return this.fail(e);
+                },
+                Poll::Ready(None) => {
+                    this.outer_ended = true;
+                    return this.fail(Error::Disconnected);
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
+
+        for index in 0..this.inners.len() {
+            let inner = &mut this.inners[index];
+
+            while !inner.ended {
+                match inner.event_rx.poll_next_unpin(cx) {
+                    Poll::Ready(Some(ProducerEvent::Data(item))) => {
+                        inner.in_flight = inner.in_flight.saturating_sub(1);
+                        this.buffer.push_back(item);
+                    },
+                    Poll::Ready(Some(ProducerEvent::End)) => {
+                        inner.ended = true;
+                    },
+                    Poll::Ready(Some(ProducerEvent::Error(e))) => {
+                        inner.ended = true;
+                        return this.fail(e);
+                    },
+                    Poll::Ready(None) => {
+                        inner.ended = true;
+                        return this.fail(Error::Disconnected);
+                    },
+                    Poll::Pending => {
+                        break;
+                    },
+                }
+            }
+        }
+
+        // whatever the ended ones didn't deliver is shared out again below
+        this.inners.retain(|inner| !inner.ended);
+
+        while this.demand > 0 {
+            match this.buffer.pop_front() {
+                Some(item) => {
+                    this.demand -= 1;
+
+                    if this.event_tx.unbounded_send(ProducerEvent::Data(item)).is_err() {
+                        // nobody is listening anymore
+                        this.cancel_all(CancelReason::Disconnected);
+                        return Poll::Ready(());
+                    }
+                },
+                None => {
+                    break;
+                },
+            }
+        }
+
+        if this.outer_ended && this.inners.is_empty() && this.buffer.is_empty() {
+            let _ = this.event_tx.unbounded_send(ProducerEvent::End);
+            return Poll::Ready(());
+        }
+
+        this.request_shares();
+        this.request_outer();
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use futures::FutureExt;
+    use crate::{MapConduit, MapProducer, RangeProducer, Conduit, Consumer, ConsumerEvent};
+
+    #[tokio::test]
+    async fn concat_in_order() {
+        let concat = Concat::new(vec![
+            RangeProducer::new(0, Some(3)),
+            RangeProducer::new(10, Some(12)),
+            RangeProducer::new(20, Some(23)),
+        ]);
+
+        let items: Vec<i64> = concat.into_stream(4).collect().await;
+        assert_eq!(items, vec![0, 1, 2, 10, 11, 20, 21, 22]);
+    }
+
+    #[tokio::test]
+    async fn demand_carries_over() {
+        let (mut a, a_out) = MapConduit::new(|x: i32| x).split();
+        let (mut b, b_out) = MapConduit::new(|x: i32| x).split();
+        let mut a_events = a.event_stream().unwrap();
+        let mut b_events = b.event_stream().unwrap();
+
+        let mut concat = Concat::new(vec![a_out, b_out]);
+        let mut events = concat.event_stream().unwrap();
+
+        concat.request(5);
+        assert_eq!(a_events.next().await, Some(ConsumerEvent::Request(5)));
+
+        a.write(1);
+        a.write(2);
+        a.end();
+
+        assert_eq!(b_events.next().await, Some(ConsumerEvent::Request(3)));
+
+        for expected in [1, 2] {
+            match events.next().await {
+                Some(ProducerEvent::Data(item)) => assert_eq!(item, expected),
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn flatten_respects_concurrency() {
+        let (mut outer_input, outer) = MapConduit::new(|p: MapProducer<i32>| p).split();
+        let mut outer_events = outer_input.event_stream().unwrap();
+        let mut inputs = Vec::new();
+
+        let flatten = FlattenBuilder::new().concurrency(2).build(outer);
+        let mut items = flatten.into_stream(10);
+        assert!(items.next().now_or_never().is_none());
+
+        // inner producers are only asked for while there's room for them
+        for _ in 0..2 {
+            assert_eq!(outer_events.next().await, Some(ConsumerEvent::Request(1)));
+
+            let (input, output) = MapConduit::new(|x: i32| x).split();
+            outer_input.write(output);
+            inputs.push(input);
+        }
+
+        tokio::task::yield_now().await;
+        assert!(outer_events.next().now_or_never().is_none());
+
+        inputs[0].write(7);
+        inputs[0].end();
+        assert_eq!(items.next().await, Some(7));
+
+        assert_eq!(outer_events.next().await, Some(ConsumerEvent::Request(1)));
+    }
+
+    #[tokio::test]
+    async fn every_inner_gets_a_request() {
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+
+        for _ in 0..3 {
+            let (mut input, output) = MapConduit::new(|x: i32| x).split();
+            let events = input.event_stream().unwrap();
+            inputs.push((input, events));
+            outputs.push(output);
+        }
+
+        let outer = StreamProducer::new(stream::iter(outputs));
+        let mut flatten = Flatten::new(outer);
+
+        // all of them start before anything is requested
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(inputs.iter_mut().all(|(_, events)| events.next().now_or_never().is_none()));
+
+        flatten.request(1);
+
+        for (_, events) in inputs.iter_mut() {
+            assert_eq!(events.next().await, Some(ConsumerEvent::Request(1)));
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_inner_does_not_stall() {
+        let (_idle, idle_out) = MapConduit::new(|x: i64| x).split();
+        let (live_out, _) = RangeProducer::new(0, None).pipe_through(MapConduit::new(|x: i64| x));
+
+        let outer = StreamProducer::new(stream::iter(vec![idle_out, live_out]));
+
+        let items: Vec<i64> = Flatten::new(outer).into_stream(1).take(5).collect().await;
+        assert_eq!(items, (0..5).collect::<Vec<i64>>());
+    }
+
+    #[tokio::test]
+    async fn flatten_unlimited() {
+        let outer = StreamProducer::new(stream::iter((0..4).map(|i| RangeProducer::new(i * 10, Some(i * 10 + 5)))));
+
+        let mut items: Vec<i64> = Flatten::new(outer).into_stream(8).collect().await;
+        items.sort();
+
+        let expected: Vec<i64> = (0..4).flat_map(|i| i * 10..i * 10 + 5).collect();
+        assert_eq!(items, expected);
+    }
+
+    #[tokio::test]
+    async fn inner_error_fails() {
+        let (a, a_out) = MapConduit::new(|x: i32| x).split();
+
+        let concat = Concat::new(vec![a_out]);
+        a.abort(Error::Disconnected);
+
+        let mut items = concat.into_stream(1);
+        assert!(items.next().await.is_none());
+        assert!(matches!(items.take_error(), Some(Error::Disconnected)));
+    }
+}
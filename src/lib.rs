@@ -1,42 +1,60 @@
-use futures::sync::mpsc;
+use futures::channel::mpsc;
 
-//mod read_adapter;
+mod read_adapter;
 mod write_adapter;
 mod sink_adapter;
 mod map_conduit;
+mod filter_conduit;
 mod range_producer;
-mod transport;
+mod stream_producer;
+mod merge_producer;
+mod flatten;
+mod zip;
+mod tee;
+pub mod transport;
 mod multiplexer;
 mod producer;
 mod consumer;
+mod error;
 
 pub mod runtime {
-    use futures::future::lazy;
-    pub fn run(f: fn()) {
-        tokio::run(lazy(move || {
-            f();
-            Ok(())
-        }));
+    use std::future::Future;
+    pub fn run<F: Future<Output = ()>>(f: F) {
+        tokio::runtime::Runtime::new()
+            .expect("tokio runtime")
+            .block_on(f);
     }
 }
 
-//pub use self::read_adapter::ReadAdapter;
+pub use self::read_adapter::{ReadAdapter, ReadAdapterBuilder};
 pub use self::write_adapter::WriteAdapter;
 pub use self::sink_adapter::SinkAdapter;
 pub use self::range_producer::{RangeProducer, RangeProducerBuilder};
+pub use self::stream_producer::StreamProducer;
+pub use self::merge_producer::{MergeProducer, MergeInputs};
+pub use self::flatten::{Flatten, FlattenBuilder, Concat};
+pub use self::zip::Zip;
+pub use self::tee::Tee;
 pub use self::map_conduit::{MapConduit, MapConsumer, MapProducer};
-//pub use self::transport::{Transport, Acceptor, WebSocketTransport, WebSocketAcceptorBuilder};
-pub use self::transport::{Transport};
-pub use self::multiplexer::{Multiplexer, MultiplexerEvent};
+pub use self::filter_conduit::{FilterConduit, FilterMapConduit};
+pub use self::transport::{
+    Transport, TransportError, Acceptor, WebSocketTransport, WebSocketAcceptor,
+    WebSocketAcceptorBuilder, WebSocketConnector, FramedTransport, TcpAcceptor, tcp_connect,
+    MemoryTransport,
+};
+#[cfg(unix)]
+pub use self::transport::{UnixAcceptor, unix_connect};
+pub use self::multiplexer::{Multiplexer, MultiplexerEvent, SenderConsumer, ProtocolError};
 pub use self::producer::{
     Producer, ProducerEvent, ProducerEventRx, ProducerEventTx,
     ProducerMessage, ProducerMessageRx, ProducerMessageTx,
-    ProducerEventEmitter,
+    ProducerEventEmitter, ProducerStream, PipeHandle, PipeStats, pipe_into,
 };
 
+pub use self::error::Error;
 pub use self::consumer::{
     Consumer, ConsumerEvent, ConsumerEventRx, ConsumerEventTx,
-    ConsumerMessage, ConsumerMessageRx, ConsumerMessageTx,
+    ConsumerMessage, ConsumerMessageRx, ConsumerMessageTx, ConsumerSink,
 };
 
 pub type Message = Vec<u8>;
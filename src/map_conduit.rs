@@ -1,14 +1,16 @@
 use super::{
-    Consumer, ConsumerEventRx, Producer, ProducerEventRx, Conduit,
+    Consumer, ConsumerEventRx, Producer, ProducerEventRx,
     ConsumerMessage, ConsumerEvent, ProducerMessage, ProducerEvent,
     ConsumerMessageTx, ProducerMessageTx,
     ConsumerMessageRx, ConsumerEventTx, ProducerMessageRx, ProducerEventTx,
-    Streamer, CancelReason,
+    Streamer, CancelReason, Error,
 };
+use std::future::Future;
 use std::marker::PhantomData;
-use futures::sync::mpsc;
-use tokio::io;
-use tokio::prelude::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc;
+use futures::StreamExt;
 
 
 #[derive(Debug)]
@@ -31,8 +33,9 @@ pub struct MapProducer<B> {
     event_rx: Option<ProducerEventRx<B>>,
 }
 
+// Shared with the filtering conduits, so the function may drop items.
 struct InnerTask<F, A, B>
-    where F: FnMut(A) -> B + Send
+    where F: FnMut(A) -> Option<B> + Send
 {
     f: F,
     c_message_rx: ConsumerMessageRx<A>,
@@ -43,76 +46,102 @@ struct InnerTask<F, A, B>
 }
 
 impl<F, A, B> InnerTask<F, A, B>
-    where F: FnMut(A) -> B + Send
+    where F: FnMut(A) -> Option<B> + Send
 {
-    fn process_producer_messages(&mut self) {
-        loop {
-            match self.p_message_rx.poll().unwrap() {
-                Async::Ready(message) => {
-                    match message {
-                        Some(ProducerMessage::Request(n)) => {
-                            // just forward the request to the consumer end
-                            (&self.c_event_tx).unbounded_send(ConsumerEvent::Request(n)).unwrap();
-                        },
-                        Some(ProducerMessage::Cancel(reason)) => {
-                            (&self.c_event_tx).unbounded_send(ConsumerEvent::Cancellation(reason)).unwrap();
-                        },
-                        None => {
-                            break;
-                        }
-                    }
+    fn process_producer_messages(&mut self, cx: &mut Context) {
+        while let Poll::Ready(message) = self.p_message_rx.poll_next_unpin(cx) {
+            // Whoever is upstream may already be gone, in which case there's
+            // nobody to forward to.
+            match message {
+                Some(ProducerMessage::Request(n)) => {
+                    // just forward the request to the consumer end
+                    let _ = self.c_event_tx.unbounded_send(ConsumerEvent::Request(n));
                 },
-                Async::NotReady => {
+                Some(ProducerMessage::Cancel(reason)) => {
+                    let _ = self.c_event_tx.unbounded_send(ConsumerEvent::Cancellation(reason));
+                    self.ended = true;
                     break;
                 },
+                // The producer end was dropped without cancelling
+                None => {
+                    let reason = CancelReason::Disconnected;
+                    let _ = self.c_event_tx.unbounded_send(ConsumerEvent::Cancellation(reason));
+                    self.ended = true;
+                    break;
+                }
             }
         }
     }
 
-    fn process_consumer_messages(&mut self) {
-        loop {
-            match self.c_message_rx.poll().unwrap() {
-                Async::Ready(message) => {
-                    match message {
-                        Some(ConsumerMessage::Write(data)) => {
-                            let mapped = (self.f)(data);
-                            (&self.p_event_tx).unbounded_send(ProducerEvent::Data(mapped)).unwrap();
-                        },
-                        Some(ConsumerMessage::End) => {
-                            (&self.p_event_tx).unbounded_send(ProducerEvent::End).unwrap();
-                            self.ended = true;
-                            break;
+    fn process_consumer_messages(&mut self, cx: &mut Context) {
+        let mut dropped = 0;
+
+        while let Poll::Ready(message) = self.c_message_rx.poll_next_unpin(cx) {
+            // Whoever is downstream may already be gone, in which case
+            // there's nobody to forward to.
+            match message {
+                Some(ConsumerMessage::Write(data)) => {
+                    match (self.f)(data) {
+                        Some(mapped) => {
+                            let _ = self.p_event_tx.unbounded_send(ProducerEvent::Data(mapped));
                         },
                         None => {
-                            break;
-                        }
+                            dropped += 1;
+                        },
                     }
                 },
-                Async::NotReady => {
+                Some(ConsumerMessage::End) => {
+                    let _ = self.p_event_tx.unbounded_send(ProducerEvent::End);
+                    self.ended = true;
+                    break;
+                },
+                Some(ConsumerMessage::Abort(e)) => {
+                    let _ = self.p_event_tx.unbounded_send(ProducerEvent::Error(e));
+                    self.ended = true;
                     break;
                 },
+                // The consumer end was dropped without ending
+                None => {
+                    let _ = self.p_event_tx.unbounded_send(ProducerEvent::Error(Error::Disconnected));
+                    self.ended = true;
+                    break;
+                }
             }
         }
+
+        // Dropped items still used up demand, so ask for replacements to
+        // make sure downstream gets everything it requested.
+        if dropped > 0 && !self.ended {
+            let _ = self.c_event_tx.unbounded_send(ConsumerEvent::Request(dropped));
+        }
     }
 }
 
+// The inner task never pins any of its fields, so it's safe to move around
+// regardless of F.
+impl<F, A, B> Unpin for InnerTask<F, A, B>
+    where F: FnMut(A) -> Option<B> + Send
+{}
+
 impl<F, A, B> Future for InnerTask<F, A, B>
-    where F: FnMut(A) -> B + Send
+    where F: FnMut(A) -> Option<B> + Send
 {
-    type Item = ();
-    type Error = io::Error;
+    type Output = ();
     
-    fn poll(&mut self) -> Poll<(), io::Error> {
-        self.process_producer_messages();
-        self.process_consumer_messages();
-        //Ok(Async::Ready(()))
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+
+        this.process_producer_messages(cx);
 
-        if self.ended {
-            Ok(Async::Ready(()))
+        if !this.ended {
+            this.process_consumer_messages(cx);
+        }
+
+        if this.ended {
+            Poll::Ready(())
         }
         else {
-            //task::current().notify();
-            Ok(Async::NotReady)
+            Poll::Pending
         }
     }
 }
@@ -124,106 +153,136 @@ impl<A, B> MapConduit<A, B>
     where A: Send + 'static,
           B: Send + 'static,
 {
-    pub fn new<F: FnMut(A) -> B + Send + 'static>(f: F) -> MapConduit<A, B> {
-
-        let (c_message_tx, c_message_rx) = mpsc::unbounded::<ConsumerMessage<A>>();
-        let (c_event_tx, c_event_rx) = mpsc::unbounded::<ConsumerEvent>();
+    pub fn new<F: FnMut(A) -> B + Send + 'static>(mut f: F) -> MapConduit<A, B> {
 
-        let (p_message_tx, p_message_rx) = mpsc::unbounded::<ProducerMessage>();
-        let (p_event_tx, p_event_rx) = mpsc::unbounded::<ProducerEvent<B>>();
-
-        let inner = InnerTask {
-            f,
-            c_message_rx,
-            c_event_tx,
-            p_message_rx,
-            p_event_tx,
-            ended: false,
-        };
-
-        tokio::spawn(inner.map_err(|e| {
-            eprintln!("{:?}", e);
-        }));
-
-        let consumer_half = MapConsumer {
-            message_tx: c_message_tx,
-            event_rx: Some(c_event_rx),
-        };
-
-        let producer_half = MapProducer {
-            message_tx: p_message_tx,
-            event_rx: Some(p_event_rx),
-        };
+        let (consumer, producer) = spawn_conduit(move |data| Some(f(data)));
 
         MapConduit {
             in_type: PhantomData,
             out_type: PhantomData,
-            consumer: consumer_half,
-            producer: producer_half,
+            consumer,
+            producer,
         }
     }
 }
 
-impl<A, B> Consumer<A> for MapConduit<A, B> {
-    fn write(&self, data: A) {
-        self.consumer.write(data);
-    }
+/// Start the task behind a conduit, returning its two halves. Items `f`
+/// maps to `None` are dropped and replaced with fresh requests upstream.
+pub(crate) fn spawn_conduit<F, A, B>(f: F) -> (MapConsumer<A>, MapProducer<B>)
+    where F: FnMut(A) -> Option<B> + Send + 'static,
+          A: Send + 'static,
+          B: Send + 'static,
+{
+    let (c_message_tx, c_message_rx) = mpsc::unbounded::<ConsumerMessage<A>>();
+    let (c_event_tx, c_event_rx) = mpsc::unbounded::<ConsumerEvent>();
+
+    let (p_message_tx, p_message_rx) = mpsc::unbounded::<ProducerMessage>();
+    let (p_event_tx, p_event_rx) = mpsc::unbounded::<ProducerEvent<B>>();
+
+    let inner = InnerTask {
+        f,
+        c_message_rx,
+        c_event_tx,
+        p_message_rx,
+        p_event_tx,
+        ended: false,
+    };
+
+    tokio::spawn(inner);
+
+    let consumer_half = MapConsumer {
+        message_tx: c_message_tx,
+        event_rx: Some(c_event_rx),
+    };
+
+    let producer_half = MapProducer {
+        message_tx: p_message_tx,
+        event_rx: Some(p_event_rx),
+    };
+
+    (consumer_half, producer_half)
+}
 
-    fn end(&self) {
-        self.consumer.end();
-    }
+// Implements Consumer, Producer and Conduit for a conduit type by
+// forwarding to its `consumer` and `producer` halves.
+macro_rules! forward_to_halves {
+    ($conduit:ident<$($param:ident),+>, $in:ident, $out:ident) => {
+        impl<$($param),+> $crate::Consumer<$in> for $conduit<$($param),+> {
+            fn write(&self, data: $in) {
+                self.consumer.write(data);
+            }
 
-    fn event_stream(&mut self) -> Option<ConsumerEventRx> {
-        self.consumer.event_stream()
-    }
+            fn end(&self) {
+                self.consumer.end();
+            }
 
-    fn set_event_stream(&mut self, event_stream: ConsumerEventRx) {
-        self.consumer.set_event_stream(event_stream);
-    }
-}
+            fn abort(&self, error: $crate::Error) {
+                self.consumer.abort(error);
+            }
 
-impl<A, B> Streamer for MapConduit<A, B> {
-    fn cancel(&mut self, _reason: CancelReason) {
-        // TODO: implement cancel
-    }
-}
+            fn event_stream(&mut self) -> Option<$crate::ConsumerEventRx> {
+                self.consumer.event_stream()
+            }
 
-impl<A, B> Producer<B> for MapConduit<A, B>
-    where B: Send + 'static
-{
-    fn request(&mut self, num_items: usize) {
-        self.producer.request(num_items);
-    }
+            fn set_event_stream(&mut self, event_stream: $crate::ConsumerEventRx) {
+                self.consumer.set_event_stream(event_stream);
+            }
+        }
 
-    fn event_stream(&mut self) -> Option<ProducerEventRx<B>> {
-        self.producer.event_stream()
-    }
+        impl<$($param),+> $crate::Streamer for $conduit<$($param),+> {
+            fn cancel(&mut self, reason: $crate::CancelReason) {
+                self.producer.cancel(reason);
+            }
+        }
 
-    fn set_event_stream(&mut self, event_stream: ProducerEventRx<B>) {
-        self.producer.set_event_stream(event_stream);
-    }
-}
+        impl<$($param),+> $crate::Producer<$out> for $conduit<$($param),+>
+            where $out: Send + 'static
+        {
+            fn request(&mut self, num_items: usize) {
+                self.producer.request(num_items);
+            }
 
+            fn event_stream(&mut self) -> Option<$crate::ProducerEventRx<$out>> {
+                self.producer.event_stream()
+            }
 
-impl<A, B> Conduit<A, B> for MapConduit<A, B>
-    where B: Send + 'static
-{
-    type ConcreteConsumer = MapConsumer<A>;
-    type ConcreteProducer = MapProducer<B>;
+            fn set_event_stream(&mut self, event_stream: $crate::ProducerEventRx<$out>) {
+                self.producer.set_event_stream(event_stream);
+            }
+        }
 
-    fn split(self) -> (MapConsumer<A>, MapProducer<B>) {
-        (self.consumer, self.producer)
-    }
+        impl<$($param),+> $crate::Conduit<$in, $out> for $conduit<$($param),+>
+            where $out: Send + 'static
+        {
+            type ConcreteConsumer = $crate::MapConsumer<$in>;
+            type ConcreteProducer = $crate::MapProducer<$out>;
+
+            fn split(self) -> ($crate::MapConsumer<$in>, $crate::MapProducer<$out>) {
+                (self.consumer, self.producer)
+            }
+        }
+    };
 }
 
+pub(crate) use forward_to_halves;
+
+forward_to_halves!(MapConduit<A, B>, A, B);
+
 
 impl<A> Consumer<A> for MapConsumer<A> {
     fn write(&self, data: A) {
-        (&self.message_tx).unbounded_send(ConsumerMessage::Write(data)).unwrap();
+        // Cancelled and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::Write(data));
     }
 
     fn end(&self) {
-        (&self.message_tx).unbounded_send(ConsumerMessage::End).unwrap();
+        // Cancelled and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::End);
+    }
+
+    fn abort(&self, error: Error) {
+        // Cancelled and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::Abort(error));
     }
 
     fn event_stream(&mut self) -> Option<ConsumerEventRx> {
@@ -237,14 +296,8 @@ impl<A> Consumer<A> for MapConsumer<A> {
 
 impl<B> Streamer for MapProducer<B> {
     fn cancel(&mut self, reason: CancelReason) {
-        match (&self.message_tx).unbounded_send(ProducerMessage::Cancel(reason)) {
-            Ok(_) => {
-            },
-            Err(_e) => {
-                // TODO: properly handle
-                //eprintln!("{:?}", e);
-            }
-        }
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Cancel(reason));
     }
 }
 
@@ -252,7 +305,7 @@ impl<B> Producer<B> for MapProducer<B>
     where B: Send + 'static
 {
     fn request(&mut self, num_items: usize) {
-        match (&self.message_tx).unbounded_send(ProducerMessage::Request(num_items)) {
+        match self.message_tx.unbounded_send(ProducerMessage::Request(num_items)) {
             Ok(_) => {
             },
             Err(_e) => {
@@ -275,49 +328,78 @@ impl<B> Producer<B> for MapProducer<B>
 #[cfg(test)]
 mod tests {
 
-    use futures::future::lazy;
     use super::*;
+    use crate::Conduit;
+
+    #[tokio::test]
+    async fn request_is_forwarded() {
+        let mut conduit = MapConduit::new(|_: i32| 0);
+
+        let mut consumer_events = Consumer::event_stream(&mut conduit).unwrap();
 
-    #[test]
-    fn request_is_forwarded() {
-        tokio::run(lazy(|| {
-            let mut conduit = MapConduit::new(|_: i32| 0);
+        conduit.request(10);
+        conduit.end();
+
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(10)));
+    }
 
-            let consumer_events = Consumer::event_stream(&mut conduit).unwrap();
+    #[tokio::test]
+    async fn split() {
+        let conduit = MapConduit::new(|x: i32| x * 2);
 
-            tokio::spawn(consumer_events.for_each(|_event| {
-                Ok(())
-            })
-            .map_err(|_| {}));
+        let (mut consumer, mut producer) = conduit.split();
 
-            conduit.request(10);
-            conduit.end();
+        let mut consumer_events = consumer.event_stream().unwrap();
+        let mut producer_events = producer.event_stream().unwrap();
 
-            Ok(())
-        }));
+        producer.request(11);
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(11)));
+
+        consumer.write(21);
+        consumer.end();
+
+        match producer_events.next().await {
+            Some(ProducerEvent::Data(value)) => assert_eq!(value, 42),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        match producer_events.next().await {
+            Some(ProducerEvent::End) => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
     }
 
-    #[test]
-    fn split() {
+    #[tokio::test]
+    async fn error_is_forwarded() {
+        let (consumer, mut producer) = MapConduit::new(|x: i32| x).split();
 
-        tokio::run(lazy(|| {
-            let conduit = MapConduit::new(|_: i32| 0);
+        let mut producer_events = producer.event_stream().unwrap();
 
-            let (mut consumer, mut producer) = conduit.split();
+        consumer.write(1);
+        consumer.abort(Error::Disconnected);
+
+        match producer_events.next().await {
+            Some(ProducerEvent::Data(1)) => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        match producer_events.next().await {
+            Some(ProducerEvent::Error(Error::Disconnected)) => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(producer_events.next().await.is_none());
+    }
 
-            let consumer_events = consumer.event_stream().unwrap();
+    #[tokio::test]
+    async fn cancel_is_forwarded() {
+        let mut conduit = MapConduit::new(|x: i32| x);
 
-            tokio::spawn(consumer_events.for_each(|_event| {
-                //println!("{:?}", event);
-                //assert!(event == ConsumerEvent::Request(12));
-                Ok(())
-            })
-            .map_err(|_| {}));
+        let mut consumer_events = Consumer::event_stream(&mut conduit).unwrap();
 
-            producer.request(11);
-            consumer.end();
+        conduit.cancel(CancelReason::Other("done".to_string()));
+        conduit.write(1);
 
-            Ok(())
-        }));
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Cancellation(CancelReason::Other("done".to_string()))));
+        assert!(consumer_events.next().await.is_none());
     }
 }
@@ -0,0 +1,472 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use super::{
+    Producer, ProducerEvent, ProducerEventRx, ProducerEventTx,
+    ProducerMessage, ProducerMessageRx, ProducerMessageTx,
+    Streamer, CancelReason, Error,
+};
+
+type BoxProducer<T> = Box<dyn Producer<T> + Send>;
+
+
+/// Merges any number of producers into one. Downstream demand is shared
+/// evenly between the inputs and their items are handed out in turn, so a
+/// busy input can't starve the others. It ends once every input has ended
+/// and every [`MergeInputs`] has been dropped. If any input fails, the rest
+/// are cancelled and the merge fails with the same error.
+#[derive(Debug)]
+pub struct MergeProducer<T> {
+    message_tx: ProducerMessageTx,
+    event_rx: Option<ProducerEventRx<T>>,
+}
+
+/// Adds inputs to a running [`MergeProducer`].
+pub struct MergeInputs<T> {
+    input_tx: mpsc::UnboundedSender<BoxProducer<T>>,
+}
+
+struct Input<T> {
+    producer: BoxProducer<T>,
+    event_rx: ProducerEventRx<T>,
+    // requested but not received yet
+    in_flight: usize,
+    buffer: VecDeque<T>,
+    ended: bool,
+}
+
+struct InnerTask<T> {
+    message_rx: ProducerMessageRx,
+    event_tx: ProducerEventTx<T>,
+    input_rx: mpsc::UnboundedReceiver<BoxProducer<T>>,
+    inputs_closed: bool,
+    inputs: Vec<Input<T>>,
+    // index of the input to take from next
+    next: usize,
+    demand: usize,
+}
+
+impl<T> MergeProducer<T>
+    where T: Send + 'static,
+{
+    pub fn new() -> (MergeProducer<T>, MergeInputs<T>) {
+        let (message_tx, message_rx) = mpsc::unbounded::<ProducerMessage>();
+        let (event_tx, event_rx) = mpsc::unbounded::<ProducerEvent<T>>();
+        let (input_tx, input_rx) = mpsc::unbounded::<BoxProducer<T>>();
+
+        let inner_task = InnerTask {
+            message_rx,
+            event_tx,
+            input_rx,
+            inputs_closed: false,
+            inputs: Vec::new(),
+            next: 0,
+            demand: 0,
+        };
+        tokio::spawn(inner_task);
+
+        let producer = MergeProducer {
+            message_tx,
+            event_rx: Some(event_rx),
+        };
+
+        (producer, MergeInputs { input_tx })
+    }
+}
+
+impl<T> MergeInputs<T>
+    where T: Send + 'static,
+{
+    pub fn add<P>(&self, producer: P)
+        where P: Producer<T> + Send + 'static,
+    {
+        // Merge already finished and channel dropped. Dropping the producer
+        // is all that's left to do.
+        let _ = self.input_tx.unbounded_send(Box::new(producer));
+    }
+}
+
+impl<T> Clone for MergeInputs<T> {
+    fn clone(&self) -> Self {
+        MergeInputs {
+            input_tx: self.input_tx.clone(),
+        }
+    }
+}
+
+impl<T> Streamer for MergeProducer<T> {
+    fn cancel(&mut self, reason: CancelReason) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Cancel(reason));
+    }
+}
+
+impl<T> Producer<T> for MergeProducer<T>
+    where T: Send + 'static,
+{
+    fn request(&mut self, num_items: usize) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Request(num_items));
+    }
+
+    fn event_stream(&mut self) -> Option<ProducerEventRx<T>> {
+        Option::take(&mut self.event_rx)
+    }
+
+    fn set_event_stream(&mut self, event_stream: ProducerEventRx<T>) {
+        self.event_rx = Some(event_stream);
+    }
+}
+
+impl<T> InnerTask<T>
+    where T: Send + 'static,
+{
+    fn cancel_inputs(&mut self, reason: CancelReason) {
+        for input in self.inputs.iter_mut() {
+            if !input.ended {
+                input.producer.cancel(reason.clone());
+            }
+        }
+
+        // inputs that were added but not picked up yet
+        while let Ok(mut producer) = self.input_rx.try_recv() {
+            producer.cancel(reason.clone());
+        }
+    }
+
+    fn fail(&mut self, e: Error) -> Poll<()> {
+        self.cancel_inputs(CancelReason::Other(e.to_string()));
+        // Consumer may already be gone, in which case there's nobody to tell
+        let _ = self.event_tx.unbounded_send(ProducerEvent::Error(e));
+        Poll::Ready(())
+    }
+
+    // Hand out buffered items one input at a time
+    fn deliver(&mut self) -> bool {
+        let num_inputs = self.inputs.len();
+        let mut checked = 0;
+
+        while self.demand > 0 && checked < num_inputs {
+            let index = self.next % num_inputs;
+            self.next = index + 1;
+
+            match self.inputs[index].buffer.pop_front() {
+                Some(item) => {
+                    checked = 0;
+                    self.demand -= 1;
+
+                    if self.event_tx.unbounded_send(ProducerEvent::Data(item)).is_err() {
+                        return false;
+                    }
+                },
+                None => {
+                    checked += 1;
+                },
+            }
+        }
+
+        true
+    }
+
+    // Split the demand evenly between the live inputs, with what doesn't
+    // split evenly going to them in turn from the next one. Each one gets at
+    // least one item though, so an idle input can't hold up the rest.
+    fn request_shares(&mut self) {
+        let num_inputs = self.inputs.len();
+        let live: Vec<usize> = (0..num_inputs)
+            .map(|offset| (self.next + offset) % num_inputs)
+            .filter(|&index| !self.inputs[index].ended)
+            .collect();
+
+        if self.demand == 0 || live.is_empty() {
+            return;
+        }
+
+        let share = self.demand / live.len();
+        let extra = self.demand % live.len();
+
+        for (turn, index) in live.into_iter().enumerate() {
+            let target = if turn < extra { share + 1 } else { share.max(1) };
+            let input = &mut self.inputs[index];
+            let outstanding = input.in_flight + input.buffer.len();
+
+            if target > outstanding {
+                input.producer.request(target - outstanding);
+                input.in_flight += target - outstanding;
+            }
+        }
+    }
+}
+
+// The boxed producers and channels are never pinned.
+impl<T> Unpin for InnerTask<T> {}
+
+impl<T> Future for InnerTask<T>
+    where T: Send + 'static,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+
+        let this = self.get_mut();
+
+        loop {
+            match this.message_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ProducerMessage::Request(num_items))) => {
+                    this.demand += num_items;
+                },
+                Poll::Ready(Some(ProducerMessage::Cancel(reason))) => {
+                    this.cancel_inputs(reason);
+                    return Poll::Ready(());
+                },
+                Poll::Ready(None) => {
+                    this.cancel_inputs(CancelReason::Disconnected);
+                    return Poll::Ready(());
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
+
+        while !this.inputs_closed {
+            match this.input_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(mut producer)) => {
+                    let event_rx = producer.event_stream().expect("no event stream");
+
+                    this.inputs.push(Input {
+                        producer,
+                        event_rx,
+                        in_flight: 0,
+                        buffer: VecDeque::new(),
+                        ended: false,
+                    });
+                },
+                Poll::Ready(None) => {
+                    this.inputs_closed = true;
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
+
+        for index in 0..this.inputs.len() {
+            let input = &mut this.inputs[index];
+
+            while !input.ended {
+                match input.event_rx.poll_next_unpin(cx) {
+                    Poll::Ready(Some(ProducerEvent::Data(item))) => {
+                        input.in_flight = input.in_flight.saturating_sub(1);
+                        input.buffer.push_back(item);
+                    },
+                    Poll::Ready(Some(ProducerEvent::End)) => {
+                        input.ended = true;
+                    },
+                    Poll::Ready(Some(ProducerEvent::Error(e))) => {
+                        input.ended = true;
+                        return this.fail(e);
+                    },
+                    Poll::Ready(None) => {
+                        input.ended = true;
+                        return this.fail(Error::Disconnected);
+                    },
+                    Poll::Pending => {
+                        break;
+                    },
+                }
+            }
+        }
+
+        if !this.deliver() {
+            // nobody is listening anymore
+            this.cancel_inputs(CancelReason::Disconnected);
+            return Poll::Ready(());
+        }
+
+        this.inputs.retain(|input| !input.ended || !input.buffer.is_empty());
+
+        if this.inputs_closed && this.inputs.is_empty() {
+            let _ = this.event_tx.unbounded_send(ProducerEvent::End);
+            return Poll::Ready(());
+        }
+
+        this.request_shares();
+
+        Poll::Pending
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use futures::FutureExt;
+    use crate::{MapConduit, RangeProducer, Conduit, Consumer, ConsumerEvent, ConsumerEventRx};
+
+    // Total of the requests that have already arrived
+    fn requested(events: &mut ConsumerEventRx) -> usize {
+        let mut total = 0;
+
+        while let Some(Some(ConsumerEvent::Request(num_items))) = events.next().now_or_never() {
+            total += num_items;
+        }
+
+        total
+    }
+
+    #[tokio::test]
+    async fn takes_turns() {
+        let (a, a_out) = MapConduit::new(|x: i32| x).split();
+        let (b, b_out) = MapConduit::new(|x: i32| x).split();
+
+        for i in 1..4 {
+            a.write(i);
+            b.write(i * 10);
+        }
+        a.end();
+        b.end();
+
+        let (merge, inputs) = MergeProducer::new();
+        inputs.add(a_out);
+        inputs.add(b_out);
+        drop(inputs);
+
+        let items: Vec<i32> = merge.into_stream(6).collect().await;
+        assert_eq!(items, vec![1, 10, 2, 20, 3, 30]);
+    }
+
+    #[tokio::test]
+    async fn ends_after_all_inputs() {
+        let (merge, inputs) = MergeProducer::new();
+        inputs.add(RangeProducer::new(0, Some(10)));
+        inputs.add(RangeProducer::new(100, Some(105)));
+
+        let mut stream = merge.into_stream(3);
+        let mut items = Vec::new();
+
+        // still open for more inputs
+        for _ in 0..15 {
+            items.push(stream.next().await.unwrap());
+        }
+        inputs.add(RangeProducer::new(200, Some(202)));
+        drop(inputs);
+
+        while let Some(item) = stream.next().await {
+            items.push(item);
+        }
+
+        items.sort();
+        let expected: Vec<i64> = (0..10).chain(100..105).chain(200..202).collect();
+        assert_eq!(items, expected);
+        assert!(stream.take_error().is_none());
+    }
+
+    #[tokio::test]
+    async fn demand_is_shared() {
+        let (mut a, a_out) = MapConduit::new(|x: i32| x).split();
+        let (mut b, b_out) = MapConduit::new(|x: i32| x).split();
+        let mut a_events = a.event_stream().unwrap();
+        let mut b_events = b.event_stream().unwrap();
+
+        let (mut merge, inputs) = MergeProducer::new();
+        inputs.add(a_out);
+        inputs.add(b_out);
+
+        merge.request(4);
+
+        assert_eq!(a_events.next().await, Some(ConsumerEvent::Request(2)));
+        assert_eq!(b_events.next().await, Some(ConsumerEvent::Request(2)));
+    }
+
+    #[tokio::test]
+    async fn every_input_gets_a_share() {
+        let (mut merge, inputs) = MergeProducer::new();
+        let mut consumers = Vec::new();
+        let mut events = Vec::new();
+
+        for _ in 0..5 {
+            let (mut consumer, out) = MapConduit::new(|x: i32| x).split();
+            events.push(consumer.event_stream().unwrap());
+            consumers.push(consumer);
+            inputs.add(out);
+        }
+
+        // less demand than inputs still gets each of them going
+        merge.request(1);
+
+        for input_events in events.iter_mut() {
+            assert_eq!(input_events.next().await, Some(ConsumerEvent::Request(1)));
+        }
+
+        // the remainder goes to the first inputs in turn
+        merge.request(6);
+        assert_eq!(events[0].next().await, Some(ConsumerEvent::Request(1)));
+        assert_eq!(events[1].next().await, Some(ConsumerEvent::Request(1)));
+        tokio::task::yield_now().await;
+
+        let totals: Vec<usize> = events.iter_mut().map(requested).collect();
+        assert_eq!(totals, vec![0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn idle_input_does_not_stall() {
+        for window in [1, 4, 16] {
+            let (_idle, idle_out) = MapConduit::new(|x: i64| x).split();
+
+            let (merge, inputs) = MergeProducer::new();
+            inputs.add(idle_out);
+            inputs.add(RangeProducer::new(0, None));
+
+            let items: Vec<i64> = merge.into_stream(window).take(40).collect().await;
+            assert_eq!(items, (0..40).collect::<Vec<i64>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_cancels_inputs() {
+        let (mut a, a_out) = MapConduit::new(|x: i32| x).split();
+        let (mut b, b_out) = MapConduit::new(|x: i32| x).split();
+        let mut a_events = a.event_stream().unwrap();
+        let mut b_events = b.event_stream().unwrap();
+
+        let (mut merge, inputs) = MergeProducer::new();
+        inputs.add(a_out);
+        inputs.add(b_out);
+
+        let reason = CancelReason::Other("done".to_string());
+        merge.cancel(reason.clone());
+
+        assert_eq!(a_events.next().await, Some(ConsumerEvent::Cancellation(reason.clone())));
+        assert_eq!(b_events.next().await, Some(ConsumerEvent::Cancellation(reason)));
+    }
+
+    #[tokio::test]
+    async fn input_error_fails_merge() {
+        let (a, a_out) = MapConduit::new(|x: i32| x).split();
+        let (mut b, b_out) = MapConduit::new(|x: i32| x).split();
+        let mut b_events = b.event_stream().unwrap();
+
+        let (merge, inputs) = MergeProducer::new();
+        inputs.add(a_out);
+        inputs.add(b_out);
+
+        a.abort(Error::Disconnected);
+
+        let mut stream = merge.into_stream(2);
+        assert!(stream.next().await.is_none());
+        assert!(matches!(stream.take_error(), Some(Error::Disconnected)));
+
+        loop {
+            match b_events.next().await {
+                Some(ConsumerEvent::Cancellation(_)) => break,
+                Some(ConsumerEvent::Request(_)) => (),
+                None => panic!("no cancellation"),
+            }
+        }
+    }
+}
@@ -1,15 +1,39 @@
 use super::{
     EventEmitter, Transport, Producer, ProducerEventRx, ProducerMessage, ProducerMessageTx,
     ProducerEvent, ProducerEventTx, ProducerMessageRx,
-    Streamer, CancelReason,
+    Consumer, ConsumerEvent, ConsumerEventRx, ConsumerEventTx,
+    ConsumerMessage, ConsumerMessageRx, ConsumerMessageTx,
+    Streamer, CancelReason, Error,
 };
-use tokio::io;
-use tokio::prelude::*;
-use futures::sync::mpsc;
-use std::collections::{HashMap, VecDeque};
+use super::transport::{MessageRx, TransportError};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
 
 use self::MessageType::*;
 
+// Stream IDs
+//
+// Whichever side opens a stream picks its ID, and both sides refer to the
+// stream by that ID for as long as it lives. The message type says whose ID
+// space a frame belongs to. CreateReceiver, StreamData, StreamEnd and
+// StreamAbort always travel from the side that opened the stream, while
+// StreamRequestData and CancelSender always travel back to it. That means each side can allocate
+// IDs without coordinating, and the same number can be in use in both
+// directions at once.
+//
+// IDs are handed out in increasing order and only come around again after
+// the u32 space wraps, so frames that were in flight for a stream that has
+// since closed won't land on a newer stream with the same ID. On the
+// receiving side, frames for a stream we cancelled are dropped until the
+// peer ends or aborts it, or opens a new stream with the same ID. A sender
+// that gets CancelSender answers with StreamEnd, unless it already ended or
+// aborted the stream, so every cancel is eventually cleared.
 enum MessageType {
     CreateReceiver = 0,
     StreamData = 1,
@@ -17,23 +41,36 @@ enum MessageType {
     CancelSender = 3,
     StreamRequestData = 4,
     ControlMessage = 5,
+    StreamAbort = 6,
 }
 
 enum MultiplexerMessage {
     SendControlMessage(Message),
-    //CreateConduit,
+    CreateConduit(Message, SenderManager),
 }
 
 pub enum MultiplexerEvent<P: Producer<Message>> {
     Conduit(P, Message),
     ControlMessage(Message),
+    Error(ProtocolError),
+    TransportError(TransportError),
     Close,
 }
 
+/// Something the peer sent doesn't follow the multiplexer protocol. The
+/// multiplexer closes the connection after reporting one of these.
+#[derive(PartialEq, Clone, Debug)]
+pub enum ProtocolError {
+    TruncatedFrame,
+    InvalidVarint,
+    UnknownMessageType(u8),
+    UnknownStream(u32),
+    DuplicateStream(u32),
+}
+
 type Message = Vec<u8>;
-type MessageRx = mpsc::UnboundedReceiver<Message>;
 //type EventTx = mpsc::UnboundedSender<Message>;
-type Id = u8;
+type Id = u32;
 type MultiplexerEventTx = mpsc::UnboundedSender<MultiplexerEvent<ReceiverProducer>>;
 type MultiplexerEventRx = mpsc::UnboundedReceiver<MultiplexerEvent<ReceiverProducer>>;
 
@@ -46,15 +83,33 @@ pub struct Multiplexer {
 struct InnerTask<T> 
     where T: Transport + Send,
 {
-    transport: T,
+    outgoing: Outgoing<T>,
     transport_done: bool,
     transport_message_rx: MessageRx,
     event_tx: MultiplexerEventTx,
     receiver_managers: HashMap<Id, ReceiverManager>,
-    available_stream_ids: VecDeque<u8>,
+    // Streams we cancelled that the peer may still have frames in flight
+    // for. Cleared once the peer ends or reuses the ID.
+    cancelled_receivers: HashSet<Id>,
+    sender_managers: HashMap<Id, SenderManager>,
+    next_stream_id: Id,
     message_rx: mpsc::UnboundedReceiver<MultiplexerMessage>,
 }
 
+// Frames waiting for the transport to be ready.
+//
+// Frames are only handed to the transport as fast as it reports being ready.
+// While it isn't, credit the peer grants with StreamRequestData is held back
+// instead of being passed on to the local producers, so a slow connection
+// slows down the writers rather than growing the queue.
+struct Outgoing<T>
+    where T: Transport + Send,
+{
+    transport: T,
+    queue: VecDeque<Message>,
+    error: Option<TransportError>,
+}
+
 pub struct ReceiverProducer {
     message_tx: ProducerMessageTx,
     event_rx: Option<ProducerEventRx<Message>>,
@@ -65,25 +120,32 @@ struct ReceiverManager {
     message_rx: ProducerMessageRx,
 }
 
+pub struct SenderConsumer {
+    message_tx: ConsumerMessageTx<Message>,
+    event_rx: Option<ConsumerEventRx>,
+}
+
+struct SenderManager {
+    event_tx: ConsumerEventTx,
+    message_rx: ConsumerMessageRx<Message>,
+    // number of items the peer has granted but not yet received
+    window: usize,
+    // part of the window passed on to the producer and not yet written
+    requested: usize,
+    pending: VecDeque<ConsumerMessage<Message>>,
+}
+
 impl Streamer for ReceiverProducer {
     fn cancel(&mut self, reason: CancelReason) {
-        match self.message_tx.unbounded_send(ProducerMessage::Cancel(reason)) {
-            Ok(_) => (),
-            // Already ended upstream and channel dropped, so just ignore 
-            Err(_) => (),
-        }
+        // Already ended upstream and channel dropped, so just ignore 
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Cancel(reason));
     }
 }
 
 impl Producer<Message> for ReceiverProducer {
     fn request(&mut self, num_items: usize) {
-        match self.message_tx.unbounded_send(ProducerMessage::Request(num_items)) {
-            Ok(_) => {
-            }
-            // Already ended upstream and channel dropped, so just ignore 
-            Err(_) => {
-            }
-        }
+        // Already ended upstream and channel dropped, so just ignore 
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Request(num_items));
     }
 
     fn event_stream(&mut self) -> Option<ProducerEventRx<Message>> {
@@ -95,6 +157,31 @@ impl Producer<Message> for ReceiverProducer {
     }
 }
 
+impl Consumer<Message> for SenderConsumer {
+    fn write(&self, data: Message) {
+        // Multiplexer has shut down and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::Write(data));
+    }
+
+    fn end(&self) {
+        // Multiplexer has shut down and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::End);
+    }
+
+    fn abort(&self, error: Error) {
+        // Multiplexer has shut down and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::Abort(error));
+    }
+
+    fn event_stream(&mut self) -> Option<ConsumerEventRx> {
+        Option::take(&mut self.event_rx)
+    }
+
+    fn set_event_stream(&mut self, event_stream: ConsumerEventRx) {
+        self.event_rx = Some(event_stream);
+    }
+}
+
 impl Multiplexer {
     pub fn new<T: Transport + Send + 'static>(mut transport: T) -> Multiplexer {
 
@@ -102,22 +189,23 @@ impl Multiplexer {
         let (message_tx, message_rx) = mpsc::unbounded::<MultiplexerMessage>();
         let (event_tx, event_rx) = mpsc::unbounded();
 
-        let mut available_stream_ids = VecDeque::new();
-        for i in 0..=255 {
-            available_stream_ids.push_back(i);
-        }
-
         let inner = InnerTask {
-            transport,
+            outgoing: Outgoing {
+                transport,
+                queue: VecDeque::new(),
+                error: None,
+            },
             transport_done: false,
             transport_message_rx,
             event_tx,
             receiver_managers: HashMap::new(),
-            available_stream_ids,
+            cancelled_receivers: HashSet::new(),
+            sender_managers: HashMap::new(),
+            next_stream_id: 0,
             message_rx,
         };
 
-        tokio::spawn(inner.map_err(|_| {}));
+        tokio::spawn(inner);
 
         Multiplexer {
             event_rx: Some(event_rx),
@@ -126,8 +214,39 @@ impl Multiplexer {
     }
 
     pub fn send_control_message(&mut self, message: Message) {
-        self.message_tx.unbounded_send(MultiplexerMessage::SendControlMessage(message))
-            .expect("Multiplexer.send_control_message");
+        // Already closed and channel dropped, so there's nobody to send to
+        let _ = self.message_tx.unbounded_send(MultiplexerMessage::SendControlMessage(message));
+    }
+
+    /// Open a new outgoing stream. The peer receives `metadata` along with
+    /// the new stream. Data written to the returned consumer is only
+    /// requested as fast as the peer grants it.
+    pub fn create_conduit(&mut self, metadata: Message) -> SenderConsumer {
+        let (message_tx, message_rx) = mpsc::unbounded::<ConsumerMessage<Message>>();
+        let (event_tx, event_rx) = mpsc::unbounded::<ConsumerEvent>();
+
+        let sender_manager = SenderManager {
+            event_tx,
+            message_rx,
+            window: 0,
+            requested: 0,
+            pending: VecDeque::new(),
+        };
+
+        let result = self.message_tx.unbounded_send(MultiplexerMessage::CreateConduit(metadata, sender_manager));
+
+        // The multiplexer has already closed, so fail the stream right away
+        if let Err(e) = result {
+            if let MultiplexerMessage::CreateConduit(_, sender_manager) = e.into_inner() {
+                let reason = CancelReason::Disconnected;
+                let _ = sender_manager.event_tx.unbounded_send(ConsumerEvent::Cancellation(reason));
+            }
+        }
+
+        SenderConsumer {
+            message_tx,
+            event_rx: Some(event_rx),
+        }
     }
 }
 
@@ -140,105 +259,238 @@ impl EventEmitter<MultiplexerEvent<ReceiverProducer>> for Multiplexer {
 impl<T> InnerTask<T> 
     where T: Transport + Send,
 {
-    fn process_messages(&mut self) {
-        loop {
-            match self.message_rx.poll().unwrap() {
-                Async::Ready(Some(message)) => {
-                    match message {
-                        MultiplexerMessage::SendControlMessage(control_message) => {
-                            let mut message = vec![ControlMessage as u8];
-                            message.extend(control_message);
-                            self.transport.send(message);
-                        }
-                        //MultiplexerMessage::CreateConduit => {
-                        //    println!("create conduit");
-                        //}
-                    }
+    fn process_messages(&mut self, cx: &mut Context) {
+        while let Poll::Ready(Some(message)) = self.message_rx.poll_next_unpin(cx) {
+            match message {
+                MultiplexerMessage::SendControlMessage(control_message) => {
+                    let mut message = vec![ControlMessage as u8];
+                    message.extend(control_message);
+                    self.outgoing.push(message);
                 },
-                Async::Ready(None) => {
-                    break;
-                },
-                Async::NotReady => {
-                    break;
+                MultiplexerMessage::CreateConduit(metadata, sender_manager) => {
+                    match self.next_stream_id() {
+                        Some(id) => {
+                            let mut message = stream_header(CreateReceiver, id);
+                            message.extend(metadata);
+                            self.outgoing.push(message);
+                            self.sender_managers.insert(id, sender_manager);
+                        },
+                        None => {
+                            let reason = CancelReason::Other(OUT_OF_STREAM_IDS.to_string());
+                            let _ = sender_manager.event_tx.unbounded_send(ConsumerEvent::Cancellation(reason));
+                        },
+                    }
                 },
             }
         }
     }
 
-    fn process_transport_messages(&mut self) {
+    fn process_transport_messages(&mut self, cx: &mut Context) {
 
         if self.transport_done {
             return;
         }
 
-        loop {
-            match self.transport_message_rx.poll().unwrap() {
-                Async::Ready(message) => {
-                    match message {
-                        Some(m) => {
-                            self.handle_message(&m);
-                        },
-                        None => {
-                            self.transport_done = true;
-                            self.event_tx.unbounded_send(MultiplexerEvent::Close).unwrap();
-                            break;
-                        }
+        while let Poll::Ready(message) = self.transport_message_rx.poll_next_unpin(cx) {
+            match message {
+                Some(Ok(m)) => {
+                    if let Err(e) = self.handle_message(&m) {
+                        let _ = self.event_tx.unbounded_send(MultiplexerEvent::Error(e.clone()));
+                        self.close(Some(e));
+                        break;
                     }
                 },
-                Async::NotReady => {
+                Some(Err(e)) => {
+                    let _ = self.event_tx.unbounded_send(MultiplexerEvent::TransportError(e));
+                    self.close(None);
                     break;
                 },
+                None => {
+                    self.close(None);
+                    break;
+                }
             }
         }
     }
 
-    fn process_receiver_messages(&mut self) {
+    // Stop reading from the transport, fail every stream that's still open
+    // and let the user know the connection is gone. Incoming streams fail
+    // with the protocol error if that's what closed the connection.
+    fn close(&mut self, cause: Option<ProtocolError>) {
+        self.transport_done = true;
+        self.outgoing.close();
+
+        for (_, receiver_manager) in self.receiver_managers.drain() {
+            let error = match cause {
+                Some(ref e) => Error::Protocol(e.clone()),
+                None => Error::Disconnected,
+            };
+            let _ = receiver_manager.event_tx.unbounded_send(ProducerEvent::Error(error));
+        }
+        self.cancelled_receivers.clear();
+
+        for (_, sender_manager) in self.sender_managers.drain() {
+            let reason = CancelReason::Disconnected;
+            let _ = sender_manager.event_tx.unbounded_send(ConsumerEvent::Cancellation(reason));
+        }
+
+        let _ = self.event_tx.unbounded_send(MultiplexerEvent::Close);
+    }
+
+    fn process_receiver_messages(&mut self, cx: &mut Context) {
 
         let mut cancel_list = Vec::new();
 
         for (stream_id, receiver_manager) in self.receiver_managers.iter_mut() {
             loop {
-                match receiver_manager.message_rx.poll().unwrap() {
-                    Async::Ready(Some(message)) => {
-                        match message {
-                            ProducerMessage::Request(num_items) => {
-                                let wire_message = vec![StreamRequestData as u8, *stream_id, num_items as u8];
-                                self.transport.send(wire_message);
-                            },
-                            ProducerMessage::Cancel(_reason) => {
-                                cancel_list.push(stream_id.clone());
-                                let wire_message = vec![CancelSender as u8, *stream_id];
-                                self.transport.send(wire_message);
+                let reason = match receiver_manager.message_rx.poll_next_unpin(cx) {
+                    Poll::Ready(Some(ProducerMessage::Request(num_items))) => {
+                        let mut wire_message = stream_header(StreamRequestData, *stream_id);
+                        encode_varint(num_items as u64, &mut wire_message);
+                        self.outgoing.push(wire_message);
+                        continue;
+                    },
+                    Poll::Ready(Some(ProducerMessage::Cancel(reason))) => reason,
+                    // The producer was dropped without cancelling
+                    Poll::Ready(None) => CancelReason::Disconnected,
+                    Poll::Pending => break,
+                };
+
+                cancel_list.push(*stream_id);
+                let mut wire_message = stream_header(CancelSender, *stream_id);
+                wire_message.extend(encode_cancel_reason(&reason));
+                self.outgoing.push(wire_message);
+                break;
+            }
+        }
+
+        for stream_id in cancel_list {
+            self.receiver_managers.remove(&stream_id);
+            self.cancelled_receivers.insert(stream_id);
+        }
+    }
+
+    fn process_sender_messages(&mut self, cx: &mut Context) {
+
+        let mut end_list = Vec::new();
+
+        for (stream_id, sender_manager) in self.sender_managers.iter_mut() {
+            loop {
+                let message = match sender_manager.message_rx.poll_next_unpin(cx) {
+                    Poll::Ready(Some(message)) => message,
+                    // The consumer was dropped. Unless it ended or aborted
+                    // first, the stream didn't finish.
+                    Poll::Ready(None) => {
+                        match sender_manager.pending.back() {
+                            Some(ConsumerMessage::End) | Some(ConsumerMessage::Abort(_)) => (),
+                            _ => {
+                                sender_manager.pending.clear();
+                                sender_manager.pending.push_back(ConsumerMessage::Abort(Error::Disconnected));
                             },
                         }
+                        break;
+                    },
+                    Poll::Pending => break,
+                };
+
+                match message {
+                    ConsumerMessage::Write(_) => {
+                        sender_manager.requested = sender_manager.requested.saturating_sub(1);
+                    },
+                    // no point sending data the peer will throw away
+                    ConsumerMessage::Abort(_) => {
+                        sender_manager.pending.clear();
                     },
-                    Async::Ready(None) => {
+                    ConsumerMessage::End => (),
+                }
+                sender_manager.pending.push_back(message);
+            }
+
+            // Only put as much on the wire as the peer has granted, and only
+            // as fast as the transport takes it. Anything beyond that waits.
+            while let Some(message) = sender_manager.pending.pop_front() {
+                match message {
+                    ConsumerMessage::Write(data) => {
+                        if sender_manager.window == 0 || !self.outgoing.poll_flush(cx) {
+                            sender_manager.pending.push_front(ConsumerMessage::Write(data));
+                            break;
+                        }
+
+                        sender_manager.window -= 1;
+
+                        let mut wire_message = stream_header(StreamData, *stream_id);
+                        wire_message.extend(data);
+                        self.outgoing.push(wire_message);
+                    },
+                    ConsumerMessage::End => {
+                        end_list.push(*stream_id);
+                        let wire_message = stream_header(StreamEnd, *stream_id);
+                        self.outgoing.push(wire_message);
                         break;
                     },
-                    Async::NotReady => {
+                    ConsumerMessage::Abort(error) => {
+                        end_list.push(*stream_id);
+                        let mut wire_message = stream_header(StreamAbort, *stream_id);
+                        wire_message.extend(encode_stream_error(&error));
+                        self.outgoing.push(wire_message);
                         break;
                     },
                 }
             }
+
+            let ungranted = sender_manager.window
+                .saturating_sub(sender_manager.requested)
+                .saturating_sub(sender_manager.pending.len());
+
+            if ungranted > 0 && self.outgoing.poll_flush(cx) {
+                sender_manager.requested += ungranted;
+                // the upstream producer may already be gone, in which case
+                // there's nobody left to grant credit to
+                let _ = sender_manager.event_tx.unbounded_send(ConsumerEvent::Request(ungranted));
+            }
         }
 
-        for stream_id in cancel_list {
-            println!("Cancel: {}", stream_id);
-            self.receiver_managers.remove(&stream_id);
-            self.available_stream_ids.push_back(stream_id);
+        for stream_id in end_list {
+            self.sender_managers.remove(&stream_id);
         }
     }
 
-    fn handle_message(&mut self, message: &[u8]) {
+    fn handle_message(&mut self, message: &[u8]) -> Result<(), ProtocolError> {
+
+        let message_type = match message.first() {
+            Some(&val) => MessageType::try_from(val)?,
+            None => return Err(ProtocolError::TruncatedFrame),
+        };
+
+        match message_type {
+            ControlMessage => {
+                let _ = self.event_tx.unbounded_send(MultiplexerEvent::ControlMessage(message[1..].to_vec()));
+                Ok(())
+            },
+            _ => {
+                let (stream_id, id_len) = decode_varint(&message[1..])?;
+
+                if stream_id > Id::MAX as u64 {
+                    return Err(ProtocolError::InvalidVarint);
+                }
+
+                let data = &message[1 + id_len..];
+                self.handle_stream_message(message_type, stream_id as Id, data)
+            },
+        }
+    }
 
-        let message_type: MessageType = message[0].into();
-        let stream_id = message[1];
-        let data = &message[2..];
+    fn handle_stream_message(&mut self, message_type: MessageType, stream_id: Id, data: &[u8]) -> Result<(), ProtocolError> {
 
         match message_type {
             CreateReceiver => {
-                println!("CreateReceiver: {}", stream_id);
-                let id = self.next_stream_id();
+                if self.receiver_managers.contains_key(&stream_id) {
+                    return Err(ProtocolError::DuplicateStream(stream_id));
+                }
+
+                // the peer has moved on from the old stream with this id
+                self.cancelled_receivers.remove(&stream_id);
+
                 let (message_tx, transport_message_rx) = mpsc::unbounded::<ProducerMessage>();
                 let (event_tx, event_rx) = mpsc::unbounded::<ProducerEvent<Message>>();
 
@@ -252,123 +504,862 @@ impl<T> InnerTask<T>
                     message_rx: transport_message_rx,
                 };
 
-                self.receiver_managers.insert(id, receiver_manager);
-                self.event_tx.unbounded_send(MultiplexerEvent::Conduit(receiver, data.to_vec())).unwrap();
+                self.receiver_managers.insert(stream_id, receiver_manager);
+                let _ = self.event_tx.unbounded_send(MultiplexerEvent::Conduit(receiver, data.to_vec()));
             },
             StreamData => {
-                //println!("StreamData");
                 match self.receiver_managers.get(&stream_id) {
                     Some(receiver_manager) => {
-                        receiver_manager.event_tx.unbounded_send(ProducerEvent::Data(data.to_vec())).unwrap();
+                        let _ = receiver_manager.event_tx.unbounded_send(ProducerEvent::Data(data.to_vec()));
                     },
                     None => {
-                        println!("data for invalid stream id. maybe it was canceled: {}", stream_id);
+                        if !self.cancelled_receivers.contains(&stream_id) {
+                            return Err(ProtocolError::UnknownStream(stream_id));
+                        }
                     }
                 }
             },
             StreamEnd => {
-                println!("StreamEnd: {}", stream_id);
-                let receiver_manager = self.receiver_managers.remove(&stream_id).expect("invalid stream id");
-                self.available_stream_ids.push_back(stream_id);
-                receiver_manager.event_tx.unbounded_send(ProducerEvent::End).unwrap();
+                match self.receiver_managers.remove(&stream_id) {
+                    Some(receiver_manager) => {
+                        let _ = receiver_manager.event_tx.unbounded_send(ProducerEvent::End);
+                    },
+                    None => {
+                        if !self.cancelled_receivers.remove(&stream_id) {
+                            return Err(ProtocolError::UnknownStream(stream_id));
+                        }
+                    }
+                }
+            },
+            StreamAbort => {
+                match self.receiver_managers.remove(&stream_id) {
+                    Some(receiver_manager) => {
+                        let error = decode_stream_error(data);
+                        let _ = receiver_manager.event_tx.unbounded_send(ProducerEvent::Error(error));
+                    },
+                    None => {
+                        if !self.cancelled_receivers.remove(&stream_id) {
+                            return Err(ProtocolError::UnknownStream(stream_id));
+                        }
+                    }
+                }
             },
             CancelSender => {
-                println!("CancelSender");
+                // The stream can end before the peer hears about it, in
+                // which case there's nothing left to cancel.
+                if let Some(sender_manager) = self.sender_managers.remove(&stream_id) {
+                    let reason = decode_cancel_reason(data);
+                    let _ = sender_manager.event_tx.unbounded_send(ConsumerEvent::Cancellation(reason));
+
+                    // lets the peer forget the stream
+                    self.outgoing.push(stream_header(StreamEnd, stream_id));
+                }
             },
             StreamRequestData => {
-                println!("StreamRequestData");
+                let (num_items, _) = decode_varint(data)?;
+
+                if num_items > usize::MAX as u64 {
+                    return Err(ProtocolError::InvalidVarint);
+                }
+
+                let num_items = num_items as usize;
+
+                // Likewise the peer may still be asking for more on a stream
+                // that already ended.
+                if let Some(sender_manager) = self.sender_managers.get_mut(&stream_id) {
+                    // passed on in process_sender_messages
+                    sender_manager.window = sender_manager.window.saturating_add(num_items);
+                }
             },
             ControlMessage => {
-                println!("ControlMessage");
-                self.event_tx.unbounded_send(MultiplexerEvent::ControlMessage(message[1..].to_vec())).unwrap();
+                // not tied to a stream, see handle_message
             },
         }
+
+        Ok(())
+    }
+
+    // Hands out IDs for streams we open, in increasing order, wrapping
+    // around and skipping any that are still in use. Returns None only if
+    // every ID is taken.
+    fn next_stream_id(&mut self) -> Option<Id> {
+        let num_in_use = self.sender_managers.len();
+
+        // Out of any num_in_use + 1 consecutive IDs at least one is free, so
+        // there's no need to look further than that.
+        for _ in 0..=num_in_use {
+            let id = self.next_stream_id;
+            self.next_stream_id = self.next_stream_id.wrapping_add(1);
+
+            if !self.sender_managers.contains_key(&id) {
+                return Some(id);
+            }
+        }
+
+        None
+    }
+
+    fn check_transport(&mut self) {
+        if self.transport_done {
+            return;
+        }
+
+        if let Some(e) = Option::take(&mut self.outgoing.error) {
+            let _ = self.event_tx.unbounded_send(MultiplexerEvent::TransportError(e));
+            self.close(None);
+        }
+    }
+}
+
+impl<T> Outgoing<T>
+    where T: Transport + Send,
+{
+    fn push(&mut self, message: Message) {
+        self.queue.push_back(message);
+    }
+
+    // Hands queued frames to the transport. Returns true if the queue is
+    // empty and the transport is ready for more. Errors are kept for
+    // InnerTask::check_transport.
+    fn poll_flush(&mut self, cx: &mut Context) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+
+        loop {
+            match self.transport.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    match self.queue.pop_front() {
+                        Some(message) => {
+                            if let Err(e) = self.transport.send(message) {
+                                self.error = Some(e);
+                                return false;
+                            }
+                        },
+                        None => return true,
+                    }
+                },
+                Poll::Ready(Err(e)) => {
+                    self.error = Some(e);
+                    return false;
+                },
+                Poll::Pending => return false,
+            }
+        }
     }
 
-    fn next_stream_id(&mut self) -> Id {
-        self.available_stream_ids.pop_front().expect("out of stream ids")
+    fn close(&mut self) {
+        self.queue.clear();
+        self.transport.close();
     }
 }
 
+// Fields are only ever accessed through &mut, never pinned, so the transport
+// doesn't need to be Unpin itself.
+impl<T> Unpin for InnerTask<T>
+    where T: Transport + Send,
+{}
+
 impl<T> Future for InnerTask<T>
     where T: Transport + Send,
 {
-    type Item = ();
-    type Error = io::Error;
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.process_messages();
-        self.process_transport_messages();
-        self.process_receiver_messages();
+        this.process_messages(cx);
+        this.process_transport_messages(cx);
+        this.process_receiver_messages(cx);
+        this.process_sender_messages(cx);
+        this.outgoing.poll_flush(cx);
+        this.check_transport();
 
-        if self.transport_done && self.receiver_managers.len() == 0 {
-            Ok(Async::Ready(()))
+        if this.transport_done && this.receiver_managers.is_empty() && this.sender_managers.is_empty() {
+            Poll::Ready(())
         }
         else {
-            Ok(Async::NotReady)
+            Poll::Pending
+        }
+    }
+}
+
+
+const OUT_OF_STREAM_IDS: &str = "out of stream ids";
+
+// Every frame except ControlMessage starts with the message type followed by
+// the stream id as a varint.
+fn stream_header(message_type: MessageType, stream_id: Id) -> Message {
+    let mut message = vec![message_type as u8];
+    encode_varint(stream_id as u64, &mut message);
+    message
+}
+
+// Unsigned LEB128. Values below 128 take a single byte, so small IDs look the
+// same as they did when they were plain u8s.
+fn encode_varint(mut value: u64, buf: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            buf.push(byte);
+            return;
         }
+
+        buf.push(byte | 0x80);
     }
 }
 
+// Returns the decoded value and how many bytes it took up.
+fn decode_varint(buf: &[u8]) -> Result<(u64, usize), ProtocolError> {
+    let mut value: u64 = 0;
+
+    for (i, byte) in buf.iter().enumerate() {
+        let bits = (byte & 0x7f) as u64;
+
+        // anything past the 64th bit
+        if i > 9 || (i == 9 && bits > 1) {
+            return Err(ProtocolError::InvalidVarint);
+        }
+
+        value |= bits << (7 * i);
+
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
 
-impl From<u8> for MessageType {
-    fn from(val: u8) -> MessageType {
+    Err(ProtocolError::TruncatedFrame)
+}
+
+// The reason travels as UTF-8 text after the stream id. Disconnected is the
+// only reason without any text.
+fn encode_cancel_reason(reason: &CancelReason) -> Vec<u8> {
+    match reason {
+        CancelReason::Disconnected => Vec::new(),
+        CancelReason::Other(text) => text.as_bytes().to_vec(),
+    }
+}
+
+fn decode_cancel_reason(data: &[u8]) -> CancelReason {
+    if data.is_empty() {
+        CancelReason::Disconnected
+    }
+    else {
+        CancelReason::Other(String::from_utf8_lossy(data).into_owned())
+    }
+}
+
+// The peer can't reconstruct the original error, so it gets the text and
+// sees the stream as cancelled. Disconnected is the only error without any.
+fn encode_stream_error(error: &Error) -> Vec<u8> {
+    match error {
+        Error::Disconnected => Vec::new(),
+        Error::Cancelled(CancelReason::Other(text)) => text.as_bytes().to_vec(),
+        _ => error.to_string().into_bytes(),
+    }
+}
+
+fn decode_stream_error(data: &[u8]) -> Error {
+    if data.is_empty() {
+        Error::Disconnected
+    }
+    else {
+        Error::Cancelled(decode_cancel_reason(data))
+    }
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = ProtocolError;
+
+    fn try_from(val: u8) -> Result<MessageType, ProtocolError> {
         match val {
-            0 => CreateReceiver,
-            1 => StreamData,
-            2 => StreamEnd,
-            3 => CancelSender,
-            4 => StreamRequestData,
-            5 => ControlMessage,
-            _ => {
-                panic!("Invalid message type: {}", val);
-            }
+            0 => Ok(CreateReceiver),
+            1 => Ok(StreamData),
+            2 => Ok(StreamEnd),
+            3 => Ok(CancelSender),
+            4 => Ok(StreamRequestData),
+            5 => Ok(ControlMessage),
+            6 => Ok(StreamAbort),
+            _ => Err(ProtocolError::UnknownMessageType(val)),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::TruncatedFrame => write!(f, "truncated frame"),
+            ProtocolError::InvalidVarint => write!(f, "invalid varint"),
+            ProtocolError::UnknownMessageType(val) => write!(f, "unknown message type: {}", val),
+            ProtocolError::UnknownStream(id) => write!(f, "unknown stream id: {}", id),
+            ProtocolError::DuplicateStream(id) => write!(f, "duplicate stream id: {}", id),
         }
     }
 }
 
+impl std::error::Error for ProtocolError {}
+
 
 
 #[cfg(test)]
 mod tests {
 
-    use futures::future::lazy;
     use super::*;
 
-    struct TestTransport {
-        message_rx: Option<MessageRx>,
+    use futures::FutureExt;
+    use futures::stream::BoxStream;
+    use crate::transport::{self, MemoryTransport, FramedTransport};
+    use tokio::io::AsyncWriteExt;
+
+    // Returns the transport for the multiplexer under test, along with the
+    // other end of it for playing the remote side, and the messages the
+    // multiplexer sends.
+    fn test_transport() -> (MemoryTransport, MemoryTransport, BoxStream<'static, Message>) {
+        let (transport, mut peer) = transport::pair();
+        let rx = peer.messages().unwrap().map(Result::unwrap).boxed();
+        (transport, peer, rx)
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let (transport, _peer, _rx) = test_transport();
+        Multiplexer::new(transport);
+    }
+
+    #[tokio::test]
+    async fn create_conduit() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+
+        let mut consumer = mux.create_conduit(vec![7, 7]);
+        let mut consumer_events = consumer.event_stream().unwrap();
+
+        assert_eq!(rx.next().await, Some(vec![CreateReceiver as u8, 0, 7, 7]));
+
+        peer.send(vec![StreamRequestData as u8, 0, 2]).unwrap();
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(2)));
+
+        consumer.write(vec![1, 2, 3]);
+        consumer.write(vec![4]);
+        consumer.end();
+
+        assert_eq!(rx.next().await, Some(vec![StreamData as u8, 0, 1, 2, 3]));
+        assert_eq!(rx.next().await, Some(vec![StreamData as u8, 0, 4]));
+        assert_eq!(rx.next().await, Some(vec![StreamEnd as u8, 0]));
+    }
+
+    #[tokio::test]
+    async fn sender_respects_window() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+
+        let consumer = mux.create_conduit(vec![]);
+
+        assert_eq!(rx.next().await, Some(vec![CreateReceiver as u8, 0]));
+
+        consumer.write(vec![1]);
+        consumer.write(vec![2]);
+        consumer.write(vec![3]);
+        consumer.end();
+
+        peer.send(vec![StreamRequestData as u8, 0, 2]).unwrap();
+
+        assert_eq!(rx.next().await, Some(vec![StreamData as u8, 0, 1]));
+        assert_eq!(rx.next().await, Some(vec![StreamData as u8, 0, 2]));
+        assert!(rx.next().now_or_never().is_none());
+
+        peer.send(vec![StreamRequestData as u8, 0, 1]).unwrap();
+
+        assert_eq!(rx.next().await, Some(vec![StreamData as u8, 0, 3]));
+        assert_eq!(rx.next().await, Some(vec![StreamEnd as u8, 0]));
+    }
+
+    #[tokio::test]
+    async fn remote_cancel() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+
+        let mut consumer = mux.create_conduit(vec![]);
+        let mut consumer_events = consumer.event_stream().unwrap();
+
+        assert_eq!(rx.next().await, Some(vec![CreateReceiver as u8, 0]));
+
+        let mut message = vec![CancelSender as u8, 0];
+        message.extend(b"no thanks");
+        peer.send(message).unwrap();
+
+        assert_eq!(
+            consumer_events.next().await,
+            Some(ConsumerEvent::Cancellation(CancelReason::Other("no thanks".to_string()))));
+        assert_eq!(consumer_events.next().await, None);
+
+        // acknowledged, so the peer can forget the stream
+        assert_eq!(rx.next().await, Some(vec![StreamEnd as u8, 0]));
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 127, 128, 255, 300, 16_383, 16_384, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            encode_varint(value, &mut buf);
+            buf.push(0xff);
+            assert_eq!(decode_varint(&buf), Ok((value, buf.len() - 1)));
+        }
+
+        assert_eq!(decode_varint(&[]), Err(ProtocolError::TruncatedFrame));
+        assert_eq!(decode_varint(&[0x80, 0x80]), Err(ProtocolError::TruncatedFrame));
+        assert_eq!(decode_varint(&[0xff; 10]), Err(ProtocolError::InvalidVarint));
+    }
+
+    #[tokio::test]
+    async fn more_than_256_streams() {
+        let (transport, _peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+
+        let consumers: Vec<SenderConsumer> = (0..300)
+            .map(|_| mux.create_conduit(vec![]))
+            .collect();
+
+        for i in 0..300 {
+            assert_eq!(rx.next().await, Some(stream_header(CreateReceiver, i)));
+        }
+
+        assert_eq!(stream_header(CreateReceiver, 299), vec![CreateReceiver as u8, 0xab, 0x02]);
+
+        consumers[299].end();
+        assert_eq!(rx.next().await, Some(vec![StreamEnd as u8, 0xab, 0x02]));
+    }
+
+    #[tokio::test]
+    async fn large_request_round_trip() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+        let mut mux_events = mux.events().unwrap();
+
+        // receiving side encodes the full count
+        peer.send(vec![CreateReceiver as u8, 0]).unwrap();
+
+        let mut producer = match mux_events.next().await {
+            Some(MultiplexerEvent::Conduit(producer, _)) => producer,
+            _ => panic!("expected conduit"),
+        };
+
+        producer.request(1000);
+
+        let request_message = rx.next().await.unwrap();
+        assert_eq!(request_message, vec![StreamRequestData as u8, 0, 0xe8, 0x07]);
+
+        // and the sending side decodes it back
+        let mut consumer = mux.create_conduit(vec![]);
+        let mut consumer_events = consumer.event_stream().unwrap();
+
+        let create_message = rx.next().await.unwrap();
+        assert_eq!(create_message[0], CreateReceiver as u8);
+
+        let mut message = stream_header(StreamRequestData, create_message[1] as Id);
+        message.extend(&request_message[2..]);
+        peer.send(message).unwrap();
+
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(1000)));
     }
 
-    impl TestTransport {
-        fn new() -> TestTransport {
+    // Feeds the frames to a fresh multiplexer and returns the error it
+    // reports. The connection must be closed right after.
+    async fn protocol_error(frames: Vec<Message>) -> ProtocolError {
+        let (transport, mut peer, _rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+        let mut mux_events = mux.events().unwrap();
 
-            let (_message_tx, message_rx) = mpsc::unbounded::<Message>();
+        for frame in frames {
+            peer.send(frame).unwrap();
+        }
 
-            TestTransport {
-                message_rx: Some(message_rx),
+        loop {
+            match mux_events.next().await {
+                Some(MultiplexerEvent::Error(e)) => {
+                    match mux_events.next().await {
+                        Some(MultiplexerEvent::Close) => return e,
+                        _ => panic!("expected close"),
+                    }
+                },
+                Some(MultiplexerEvent::Conduit(..)) => (),
+                _ => panic!("expected error"),
             }
         }
     }
 
-    impl Transport for TestTransport {
-        fn send(&mut self, _message: Message) {
-            //self.socket.send(message);
+    #[tokio::test]
+    async fn malformed_frames() {
+        assert_eq!(protocol_error(vec![vec![]]).await, ProtocolError::TruncatedFrame);
+        assert_eq!(protocol_error(vec![vec![StreamData as u8]]).await, ProtocolError::TruncatedFrame);
+        assert_eq!(protocol_error(vec![vec![StreamData as u8, 0x80]]).await, ProtocolError::TruncatedFrame);
+        assert_eq!(
+            protocol_error(vec![vec![CreateReceiver as u8, 0xff, 0xff, 0xff, 0xff, 0x7f]]).await,
+            ProtocolError::InvalidVarint);
+        assert_eq!(protocol_error(vec![vec![9, 0]]).await, ProtocolError::UnknownMessageType(9));
+        assert_eq!(protocol_error(vec![vec![StreamData as u8, 5, 1]]).await, ProtocolError::UnknownStream(5));
+        assert_eq!(protocol_error(vec![vec![StreamEnd as u8, 5]]).await, ProtocolError::UnknownStream(5));
+        assert_eq!(
+            protocol_error(vec![vec![CreateReceiver as u8, 0], vec![CreateReceiver as u8, 0]]).await,
+            ProtocolError::DuplicateStream(0));
+        assert_eq!(
+            protocol_error(vec![vec![StreamRequestData as u8, 0]]).await,
+            ProtocolError::TruncatedFrame);
+    }
+
+    #[tokio::test]
+    async fn late_frames_for_cancelled_stream() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+        let mut mux_events = mux.events().unwrap();
+
+        peer.send(vec![CreateReceiver as u8, 0]).unwrap();
+
+        let mut producer = match mux_events.next().await {
+            Some(MultiplexerEvent::Conduit(producer, _)) => producer,
+            _ => panic!("expected conduit"),
+        };
+
+        producer.cancel(CancelReason::Disconnected);
+        assert_eq!(rx.next().await, Some(vec![CancelSender as u8, 0]));
+
+        // already in flight when the peer got the cancel
+        peer.send(vec![StreamData as u8, 0, 1]).unwrap();
+        peer.send(vec![StreamEnd as u8, 0]).unwrap();
+        peer.send(vec![ControlMessage as u8, 42]).unwrap();
+
+        match mux_events.next().await {
+            Some(MultiplexerEvent::ControlMessage(message)) => assert_eq!(message, vec![42]),
+            _ => panic!("expected control message"),
         }
 
-        fn messages(&mut self) -> Option<MessageRx> {
-            Option::take(&mut self.message_rx)
+        // the stream is forgotten once the peer has ended it
+        peer.send(vec![StreamData as u8, 0, 2]).unwrap();
+
+        match mux_events.next().await {
+            Some(MultiplexerEvent::Error(e)) => assert_eq!(e, ProtocolError::UnknownStream(0)),
+            _ => panic!("expected error"),
         }
     }
 
-    #[test]
-    fn create() {
-        tokio::run(lazy(|| {
-            Multiplexer::new(TestTransport::new());
-            Ok(())
-        }));
+    #[tokio::test]
+    async fn dropped_consumer_aborts() {
+        let (transport, _peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+
+        let consumer = mux.create_conduit(vec![]);
+        assert_eq!(rx.next().await, Some(vec![CreateReceiver as u8, 0]));
+
+        consumer.write(vec![1]);
+        drop(consumer);
+
+        // queued data is dropped in favour of the abort
+        assert_eq!(rx.next().await, Some(vec![StreamAbort as u8, 0]));
     }
 
-    #[test]
-    fn transfer_largefile() {
+    #[tokio::test]
+    async fn dropped_consumer_after_end() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+
+        let consumer = mux.create_conduit(vec![]);
+        assert_eq!(rx.next().await, Some(vec![CreateReceiver as u8, 0]));
+
+        consumer.write(vec![1]);
+        consumer.end();
+        drop(consumer);
+
+        peer.send(vec![StreamRequestData as u8, 0, 1]).unwrap();
+
+        assert_eq!(rx.next().await, Some(vec![StreamData as u8, 0, 1]));
+        assert_eq!(rx.next().await, Some(vec![StreamEnd as u8, 0]));
+    }
+
+    #[tokio::test]
+    async fn dropped_producer_cancels() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+        let mut mux_events = mux.events().unwrap();
+
+        peer.send(vec![CreateReceiver as u8, 0]).unwrap();
+
+        match mux_events.next().await {
+            Some(MultiplexerEvent::Conduit(producer, _)) => drop(producer),
+            _ => panic!("expected conduit"),
+        }
+
+        assert_eq!(rx.next().await, Some(vec![CancelSender as u8, 0]));
+
+        // late frames are dropped like for any other cancelled stream
+        peer.send(vec![StreamData as u8, 0, 1]).unwrap();
+        peer.send(vec![ControlMessage as u8, 42]).unwrap();
+
+        match mux_events.next().await {
+            Some(MultiplexerEvent::ControlMessage(message)) => assert_eq!(message, vec![42]),
+            _ => panic!("expected control message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn abort() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+        let mut mux_events = mux.events().unwrap();
+
+        // outgoing: queued data is dropped in favour of the abort
+        let consumer = mux.create_conduit(vec![]);
+        assert_eq!(rx.next().await, Some(vec![CreateReceiver as u8, 0]));
+
+        consumer.write(vec![1]);
+        consumer.abort(Error::Cancelled(CancelReason::Other("oops".to_string())));
+
+        let mut expected = vec![StreamAbort as u8, 0];
+        expected.extend(b"oops");
+        assert_eq!(rx.next().await, Some(expected));
+
+        // incoming
+        peer.send(vec![CreateReceiver as u8, 3]).unwrap();
+
+        let mut producer = match mux_events.next().await {
+            Some(MultiplexerEvent::Conduit(producer, _)) => producer,
+            _ => panic!("expected conduit"),
+        };
+        let mut producer_events = producer.event_stream().unwrap();
+
+        let mut message = vec![StreamAbort as u8, 3];
+        message.extend(b"disk full");
+        peer.send(message).unwrap();
+
+        match producer_events.next().await {
+            Some(ProducerEvent::Error(Error::Cancelled(CancelReason::Other(text)))) => {
+                assert_eq!(text, "disk full");
+            },
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(producer_events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn transport_close_fails_open_streams() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+        let mut mux_events = mux.events().unwrap();
+
+        peer.send(vec![CreateReceiver as u8, 0]).unwrap();
+
+        let mut producer = match mux_events.next().await {
+            Some(MultiplexerEvent::Conduit(producer, _)) => producer,
+            _ => panic!("expected conduit"),
+        };
+        let mut producer_events = producer.event_stream().unwrap();
+
+        let mut consumer = mux.create_conduit(vec![]);
+        let mut consumer_events = consumer.event_stream().unwrap();
+        assert_eq!(rx.next().await.unwrap()[0], CreateReceiver as u8);
+
+        drop(peer);
+
+        match mux_events.next().await {
+            Some(MultiplexerEvent::Close) => (),
+            _ => panic!("expected close"),
+        }
+
+        match producer_events.next().await {
+            Some(ProducerEvent::Error(Error::Disconnected)) => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(producer_events.next().await.is_none());
+
+        assert_eq!(
+            consumer_events.next().await,
+            Some(ConsumerEvent::Cancellation(CancelReason::Disconnected)));
+        assert!(consumer_events.next().await.is_none());
+
+        // the multiplexer is gone, so new streams fail immediately
+        let mut late_consumer = mux.create_conduit(vec![]);
+        let mut late_events = late_consumer.event_stream().unwrap();
+        assert_eq!(
+            late_events.next().await,
+            Some(ConsumerEvent::Cancellation(CancelReason::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn peer_ids_are_kept() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+        let mut mux_events = mux.events().unwrap();
+
+        // Our own stream takes ID 0, which the peer is free to use for its
+        // streams as well.
+        let mut consumer = mux.create_conduit(vec![]);
+        let mut consumer_events = consumer.event_stream().unwrap();
+        assert_eq!(rx.next().await, Some(vec![CreateReceiver as u8, 0]));
+
+        peer.send(vec![CreateReceiver as u8, 7]).unwrap();
+        peer.send(vec![CreateReceiver as u8, 0]).unwrap();
+
+        let mut producers = Vec::new();
+        for _ in 0..2 {
+            match mux_events.next().await {
+                Some(MultiplexerEvent::Conduit(producer, _)) => producers.push(producer),
+                _ => panic!("expected conduit"),
+            }
+        }
+
+        let mut events_7 = producers[0].event_stream().unwrap();
+        let mut events_0 = producers[1].event_stream().unwrap();
+
+        peer.send(vec![StreamData as u8, 0, 10]).unwrap();
+        peer.send(vec![StreamData as u8, 7, 17]).unwrap();
+        peer.send(vec![StreamRequestData as u8, 0, 3]).unwrap();
+
+        match events_0.next().await {
+            Some(ProducerEvent::Data(data)) => assert_eq!(data, vec![10]),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        match events_7.next().await {
+            Some(ProducerEvent::Data(data)) => assert_eq!(data, vec![17]),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(3)));
+
+        producers[0].request(2);
+        assert_eq!(rx.next().await, Some(vec![StreamRequestData as u8, 7, 2]));
+    }
+
+    #[tokio::test]
+    async fn ended_ids_are_not_reused() {
+        let (transport, mut peer, mut rx) = test_transport();
+        let mut mux = Multiplexer::new(transport);
+
+        let first = mux.create_conduit(vec![]);
+        assert_eq!(rx.next().await, Some(vec![CreateReceiver as u8, 0]));
+
+        first.end();
+        assert_eq!(rx.next().await, Some(vec![StreamEnd as u8, 0]));
+
+        let mut second = mux.create_conduit(vec![]);
+        let mut second_events = second.event_stream().unwrap();
+        assert_eq!(rx.next().await, Some(vec![CreateReceiver as u8, 1]));
+
+        // sent by the peer before it saw the first stream end
+        peer.send(vec![StreamRequestData as u8, 0, 5]).unwrap();
+        peer.send(vec![StreamRequestData as u8, 1, 1]).unwrap();
+
+        assert_eq!(second_events.next().await, Some(ConsumerEvent::Request(1)));
+    }
+
+    #[tokio::test]
+    async fn transfer_largefile() {
+        let (a, b) = transport::pair();
+        let mut sender_mux = Multiplexer::new(a);
+        let mut receiver_mux = Multiplexer::new(b);
+        let mut receiver_events = receiver_mux.events().unwrap();
+
+        let chunk_size = 64 * 1024;
+        let num_chunks = 256;
+
+        let mut consumer = sender_mux.create_conduit(b"largefile".to_vec());
+        let mut consumer_events = consumer.event_stream().unwrap();
+
+        tokio::spawn(async move {
+            let mut sent = 0;
+
+            while let Some(ConsumerEvent::Request(num_items)) = consumer_events.next().await {
+                for _ in 0..num_items {
+                    if sent < num_chunks {
+                        consumer.write(vec![65; chunk_size]);
+                        sent += 1;
+                    }
+                }
+
+                if sent == num_chunks {
+                    consumer.end();
+                    break;
+                }
+            }
+        });
+
+        let mut producer = match receiver_events.next().await {
+            Some(MultiplexerEvent::Conduit(producer, metadata)) => {
+                assert_eq!(metadata, b"largefile".to_vec());
+                producer
+            },
+            _ => panic!("expected conduit"),
+        };
+        let mut producer_events = producer.event_stream().unwrap();
+
+        producer.request(8);
+
+        let mut bytes_received = 0;
+
+        loop {
+            match producer_events.next().await {
+                Some(ProducerEvent::Data(data)) => {
+                    bytes_received += data.len();
+                    producer.request(1);
+                },
+                Some(ProducerEvent::End) => break,
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+
+        assert_eq!(bytes_received, chunk_size * num_chunks);
+    }
+
+    #[tokio::test]
+    async fn saturated_transport_holds_credit() {
+        let (transport, mut peer) = transport::pair_with_capacity(2);
+        let mut rx = peer.messages().unwrap();
+        let mut mux = Multiplexer::new(transport);
+        let mut mux_events = mux.events().unwrap();
+
+        let mut consumer = mux.create_conduit(vec![]);
+        let mut consumer_events = consumer.event_stream().unwrap();
+
+        peer.send(vec![StreamRequestData as u8, 0, 1]).unwrap();
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(1)));
+
+        // CreateReceiver and this fill the transport
+        consumer.write(vec![1]);
+
+        peer.send(vec![StreamRequestData as u8, 0, 5]).unwrap();
+        peer.send(vec![ControlMessage as u8, 42]).unwrap();
+
+        match mux_events.next().await {
+            Some(MultiplexerEvent::ControlMessage(message)) => assert_eq!(message, vec![42]),
+            _ => panic!("expected control message"),
+        }
+
+        assert!(consumer_events.next().now_or_never().is_none());
+
+        assert_eq!(rx.next().await, Some(Ok(vec![CreateReceiver as u8, 0])));
+        assert_eq!(rx.next().await, Some(Ok(vec![StreamData as u8, 0, 1])));
+
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(5)));
+    }
+
+    #[tokio::test]
+    async fn transport_failure() {
+        let (mut raw, other) = tokio::io::duplex(64);
+        let mut mux = Multiplexer::new(FramedTransport::new(other));
+        let mut mux_events = mux.events().unwrap();
+
+        // a frame length far beyond what the transport accepts
+        raw.write_all(&[0xff; 4]).await.unwrap();
+
+        match mux_events.next().await {
+            Some(MultiplexerEvent::TransportError(TransportError::Failed(_))) => (),
+            _ => panic!("expected transport error"),
+        }
+
+        match mux_events.next().await {
+            Some(MultiplexerEvent::Close) => (),
+            _ => panic!("expected close"),
+        }
     }
 }
@@ -1,7 +1,13 @@
-use futures::sync::mpsc;
-use tokio::prelude::*;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use futures::channel::{mpsc, oneshot};
+use futures::stream::{FusedStream, Stream};
+use futures::{ready, StreamExt};
+use tokio::task::JoinHandle;
 
-use super::{CancelReason, Streamer, Consumer, ConsumerEvent, Conduit};
+use super::{CancelReason, Streamer, Consumer, ConsumerEvent, Conduit, Error};
 
 pub type ProducerEventRx<T> = mpsc::UnboundedReceiver<ProducerEvent<T>>;
 pub type ProducerEventTx<T> = mpsc::UnboundedSender<ProducerEvent<T>>;
@@ -20,6 +26,8 @@ pub enum ProducerMessage {
 pub enum ProducerEvent<T> {
     Data(T),
     End,
+    /// The stream was aborted. Like `End`, nothing follows it.
+    Error(Error),
 }
 
 pub trait Producer<T> : Streamer
@@ -29,15 +37,17 @@ pub trait Producer<T> : Streamer
     fn event_stream(&mut self) -> Option<ProducerEventRx<T>>;
     fn set_event_stream(&mut self, event_stream: ProducerEventRx<T>);
     //fn events(&mut self) -> ProducerEventEmitter<T>;
-    fn pipe_into<C>(self, consumer: C)
+    fn pipe_into<C>(self, consumer: C) -> PipeHandle
         where Self: Sized + Send + 'static,
               C: Consumer<T> + Sized + Send + 'static,
               T: Send + 'static,
     {
-        pipe_into(self, consumer);
+        pipe_into(self, consumer)
     }
 
-    fn pipe_through<C, U>(self, conduit: C) -> C::ConcreteProducer
+    /// Pipe into the consumer half of `conduit`. Returns the producer half
+    /// to carry on from, along with the handle for this leg of the pipe.
+    fn pipe_through<C, U>(self, conduit: C) -> (C::ConcreteProducer, PipeHandle)
         where Self: Sized + Send + 'static,
               C: Conduit<T, U> + Sized + Send + 'static,
               T: Send + 'static,
@@ -45,9 +55,9 @@ pub trait Producer<T> : Streamer
               C::ConcreteConsumer: Send,
     {
         let (consumer, producer) = conduit.split();
-        pipe_into(self, consumer);
+        let handle = pipe_into(self, consumer);
 
-        producer
+        (producer, handle)
     }
 
     fn events(&mut self) -> ProducerEventEmitter<T> {
@@ -56,6 +66,39 @@ pub trait Producer<T> : Streamer
             
         ProducerEventEmitter::new(event_rx)
     }
+
+    /// Consume the producer as a `Stream`, keeping up to `window` items
+    /// requested ahead of what has been taken from it. The stream ends on
+    /// `End` or `Error`, see [`ProducerStream::take_error`] to tell them
+    /// apart. Dropping it before then cancels the producer.
+    fn into_stream(mut self, window: usize) -> ProducerStream<T, Self>
+        where Self: Sized,
+    {
+        assert!(window > 0, "window must be at least 1");
+
+        let event_rx = self.event_stream().expect("event stream");
+
+        ProducerStream {
+            producer: self,
+            event_rx,
+            window,
+            started: false,
+            done: false,
+            error: None,
+        }
+    }
+}
+
+pub struct ProducerStream<T, P>
+    where T: Send + 'static,
+          P: Producer<T>,
+{
+    producer: P,
+    event_rx: ProducerEventRx<T>,
+    window: usize,
+    started: bool,
+    done: bool,
+    error: Option<Error>,
 }
 
 pub struct ProducerEventEmitter<T>
@@ -73,47 +116,372 @@ impl<T> ProducerEventEmitter<T>
         }
     }
 
-    pub fn for_each<C: FnMut(ProducerEvent<T>) + Send + 'static>(&mut self, mut callback: C) {
-        let rx = Option::take(&mut self.event_rx).expect("take event_rx");
+    pub fn for_each<C: FnMut(ProducerEvent<T>) + Send + 'static>(&mut self, mut callback: C) -> JoinHandle<()> {
+        let mut rx = Option::take(&mut self.event_rx).expect("take event_rx");
 
-        tokio::spawn(rx.for_each(move |event| {
-            callback(event);
-            Ok(())
-        }));
+        tokio::spawn(async move {
+            while let Some(event) = rx.next().await {
+                callback(event);
+            }
+        })
     }
 }
 
-pub fn pipe_into<T, P, C>(mut producer: P, mut consumer: C)
+impl<T, P> ProducerStream<T, P>
     where T: Send + 'static,
-          P: Producer<T> + Send + 'static,
-          C: Consumer<T> + Send + 'static
+          P: Producer<T>,
 {
-    let consumer_events = consumer.event_stream().expect("no event stream");
+    /// If the stream ended because the producer failed, the error it failed
+    /// with.
+    pub fn take_error(&mut self) -> Option<Error> {
+        Option::take(&mut self.error)
+    }
+}
 
-    producer.events().for_each(move |event| {
-        match event {
-            ProducerEvent::Data(data) => {
-                consumer.write(data);
+// Neither the producer nor the event channel are ever pinned.
+impl<T, P> Unpin for ProducerStream<T, P>
+    where T: Send + 'static,
+          P: Producer<T>,
+{}
+
+impl<T, P> Stream for ProducerStream<T, P>
+    where T: Send + 'static,
+          P: Producer<T>,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        // Nothing is requested until someone actually wants items
+        if !this.started {
+            this.started = true;
+            this.producer.request(this.window);
+        }
+
+        match ready!(this.event_rx.poll_next_unpin(cx)) {
+            Some(ProducerEvent::Data(data)) => {
+                // keep the window full
+                this.producer.request(1);
+                Poll::Ready(Some(data))
+            },
+            Some(ProducerEvent::End) => {
+                this.done = true;
+                Poll::Ready(None)
             },
-            ProducerEvent::End => {
-                consumer.end();
+            Some(ProducerEvent::Error(e)) => {
+                this.done = true;
+                this.error = Some(e);
+                Poll::Ready(None)
+            },
+            // the producer went away without saying why
+            None => {
+                this.done = true;
+                this.error = Some(Error::Disconnected);
+                Poll::Ready(None)
             },
         }
+    }
+}
+
+impl<T, P> FusedStream for ProducerStream<T, P>
+    where T: Send + 'static,
+          P: Producer<T>,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<T, P> Drop for ProducerStream<T, P>
+    where T: Send + 'static,
+          P: Producer<T>,
+{
+    fn drop(&mut self) {
+        if !self.done {
+            self.producer.cancel(CancelReason::Other(STREAM_DROPPED.to_string()));
+        }
+    }
+}
+
+const STREAM_DROPPED: &str = "stream dropped";
+
+/// A running pipe, see [`pipe_into`]. Resolves once the consumer is done
+/// with the stream. Dropping the handle leaves the pipe running.
+pub struct PipeHandle {
+    task: JoinHandle<Result<PipeStats, Error>>,
+    abort_tx: Option<oneshot::Sender<()>>,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct PipeStats {
+    /// Number of items that went through the pipe
+    pub items: usize,
+    pub duration: Duration,
+}
+
+impl PipeHandle {
+    pub(crate) fn new(task: JoinHandle<Result<PipeStats, Error>>, abort_tx: oneshot::Sender<()>) -> PipeHandle {
+        PipeHandle {
+            task,
+            abort_tx: Some(abort_tx),
+        }
+    }
+
+    /// Cancel the producer and end the consumer. The handle then resolves
+    /// to `Error::Cancelled`.
+    pub fn abort(&mut self) {
+        if let Some(abort_tx) = Option::take(&mut self.abort_tx) {
+            // Already finished and receiver dropped, so just ignore
+            let _ = abort_tx.send(());
+        }
+    }
+}
+
+impl Future for PipeHandle {
+    type Output = Result<PipeStats, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match ready!(Pin::new(&mut self.task).poll(cx)) {
+            Ok(result) => Poll::Ready(result),
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // the runtime is shutting down
+            Err(_) => Poll::Ready(Err(Error::Disconnected)),
+        }
+    }
+}
+
+pub(crate) const PIPE_ABORTED: &str = "pipe aborted";
+
+/// Forward data from `producer` to `consumer` and demand back the other
+/// way. The pipe succeeds once the producer has ended and the consumer has
+/// dropped its events, meaning it has finished with the data. It fails if
+/// the producer fails or the consumer cancels, after passing that on to the
+/// other side.
+pub fn pipe_into<T, P, C>(mut producer: P, mut consumer: C) -> PipeHandle
+    where T: Send + 'static,
+          P: Producer<T> + Send + 'static,
+          C: Consumer<T> + Send + 'static
+{
+    let mut producer_events = producer.event_stream().expect("no event stream");
+    let mut consumer_events = consumer.event_stream().expect("no event stream");
+    let (abort_tx, mut abort_rx) = oneshot::channel::<()>();
+
+    let task = tokio::spawn(async move {
+        let start = Instant::now();
+        let mut items = 0;
+        let mut ended = false;
+        let mut handle_dropped = false;
+
+        loop {
+            tokio::select! {
+                event = producer_events.next(), if !ended => {
+                    match event {
+                        Some(ProducerEvent::Data(data)) => {
+                            items += 1;
+                            consumer.write(data);
+                        },
+                        Some(ProducerEvent::End) => {
+                            consumer.end();
+                            ended = true;
+                        },
+                        Some(ProducerEvent::Error(e)) => {
+                            consumer.abort(e.clone());
+                            return Err(e);
+                        },
+                        None => {
+                            consumer.abort(Error::Disconnected);
+                            return Err(Error::Disconnected);
+                        },
+                    }
+                },
+                event = consumer_events.next() => {
+                    match event {
+                        Some(ConsumerEvent::Request(num_items)) => {
+                            if !ended {
+                                producer.request(num_items);
+                            }
+                        },
+                        Some(ConsumerEvent::Cancellation(reason)) => {
+                            if !ended {
+                                producer.cancel(reason.clone());
+                            }
+                            return Err(Error::Cancelled(reason));
+                        },
+                        None => {
+                            if ended {
+                                return Ok(PipeStats {
+                                    items,
+                                    duration: start.elapsed(),
+                                });
+                            }
+
+                            producer.cancel(CancelReason::Disconnected);
+                            return Err(Error::Disconnected);
+                        },
+                    }
+                },
+                result = &mut abort_rx, if !handle_dropped => {
+                    match result {
+                        Ok(()) => {
+                            let reason = CancelReason::Other(PIPE_ABORTED.to_string());
+                            if !ended {
+                                producer.cancel(reason.clone());
+                                consumer.end();
+                            }
+                            return Err(Error::Cancelled(reason));
+                        },
+                        Err(_) => {
+                            handle_dropped = true;
+                        },
+                    }
+                },
+            }
+        }
     });
 
-    tokio::spawn(consumer_events.for_each(move |event| {
-        match event {
-            ConsumerEvent::Request(num_items) => {
-                producer.request(num_items);
-            },
-            ConsumerEvent::Cancellation(reason) => {
-                producer.cancel(reason);
-            },
+    PipeHandle::new(task, abort_tx)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use futures::FutureExt;
+    use crate::{RangeProducer, MapConduit, ReadAdapter, StreamProducer};
+
+    #[tokio::test]
+    async fn into_stream() {
+        let producer = RangeProducer::new(0, Some(10));
+
+        let values: Vec<i64> = producer.into_stream(3).collect().await;
+        assert_eq!(values, (0..10).collect::<Vec<i64>>());
+    }
+
+    #[tokio::test]
+    async fn into_stream_keeps_window() {
+        let (mut consumer, producer) = MapConduit::new(|x: i32| x).split();
+        let mut consumer_events = consumer.event_stream().unwrap();
+
+        let mut stream = producer.into_stream(4);
+        assert!(stream.next().now_or_never().is_none());
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(4)));
+
+        consumer.write(7);
+        assert_eq!(stream.next().await, Some(7));
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(1)));
+    }
+
+    #[tokio::test]
+    async fn into_stream_error() {
+        let (consumer, producer) = MapConduit::new(|x: i32| x).split();
+
+        let mut stream = producer.into_stream(1);
+
+        consumer.write(1);
+        consumer.abort(Error::Disconnected);
+
+        assert_eq!(stream.next().await, Some(1));
+        assert_eq!(stream.next().await, None);
+        assert!(stream.is_terminated());
+        assert!(matches!(stream.take_error(), Some(Error::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn pipe_stats() {
+        let producer = RangeProducer::new(0, Some(10));
+        let (consumer, squares) = MapConduit::new(|x: i64| x * x).split();
+
+        let handle = producer.pipe_into(consumer);
+        let values: Vec<i64> = squares.into_stream(2).collect().await;
+
+        assert_eq!(values, (0..10).map(|x| x * x).collect::<Vec<i64>>());
+        assert_eq!(handle.await.unwrap().items, 10);
+    }
+
+    #[tokio::test]
+    async fn pipe_cancelled() {
+        let producer = RangeProducer::new(0, None);
+        let (consumer, mut out) = MapConduit::new(|x: i64| x).split();
+
+        let handle = producer.pipe_into(consumer);
+        out.cancel(CancelReason::Other("no more".to_string()));
+
+        match handle.await {
+            Err(Error::Cancelled(CancelReason::Other(reason))) => assert_eq!(reason, "no more"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_reaches_source() {
+        let producer = RangeProducer::new(0, None);
+
+        let (doubled, first) = producer.pipe_through(MapConduit::new(|x: i64| x * 2));
+        let (tripled, second) = doubled.pipe_through(MapConduit::new(|x: i64| x * 3));
+
+        let mut out = tripled.into_stream(4);
+        assert_eq!(out.next().await, Some(0));
+        assert_eq!(out.next().await, Some(6));
+        drop(out);
+
+        for handle in [second, first] {
+            match handle.await {
+                Err(Error::Cancelled(CancelReason::Other(reason))) => assert_eq!(reason, "stream dropped"),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pipe_error() {
+        let producer = ReadAdapter::new(tokio::fs::File::open("/nonexistent/omnistreams"));
+        let (consumer, out) = MapConduit::new(|x: Vec<u8>| x).split();
+
+        let handle = producer.pipe_into(consumer);
+        let mut out = out.into_stream(1);
+
+        assert!(out.next().await.is_none());
+        assert!(matches!(out.take_error(), Some(Error::Io(_))));
+        assert!(matches!(handle.await, Err(Error::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn pipe_abort() {
+        let producer = StreamProducer::new(futures::stream::pending::<i32>());
+        let (consumer, out) = MapConduit::new(|x: i32| x).split();
+
+        let mut handle = producer.pipe_into(consumer);
+        let mut out = out.into_stream(1);
+
+        handle.abort();
+
+        match handle.await {
+            Err(Error::Cancelled(CancelReason::Other(reason))) => assert_eq!(reason, PIPE_ABORTED),
+            other => panic!("unexpected result: {:?}", other),
         }
-        
-        Ok(())
-    })
-    .map_err(|e| {
-        println!("error {:?}", e);
-    }));
+
+        // the consumer was ended rather than failed
+        assert!(out.next().await.is_none());
+        assert!(out.take_error().is_none());
+    }
+
+    #[tokio::test]
+    async fn into_stream_drop_cancels() {
+        let (mut consumer, producer) = MapConduit::new(|x: i32| x).split();
+        let mut consumer_events = consumer.event_stream().unwrap();
+
+        let mut stream = producer.into_stream(2);
+        assert!(stream.next().now_or_never().is_none());
+        drop(stream);
+
+        assert_eq!(consumer_events.next().await, Some(ConsumerEvent::Request(2)));
+        assert_eq!(
+            consumer_events.next().await,
+            Some(ConsumerEvent::Cancellation(CancelReason::Other(STREAM_DROPPED.to_string()))));
+    }
 }
@@ -1,6 +1,8 @@
-use tokio::io;
-use tokio::prelude::*;
-use futures::sync::mpsc;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc;
+use futures::StreamExt;
 use super::{
     Producer, ProducerEvent, ProducerEventRx, ProducerEventTx,
     ProducerMessage, ProducerMessageRx, ProducerMessageTx,
@@ -20,6 +22,12 @@ pub struct RangeProducerBuilder {
     stop: Option<Item>,
 }
 
+impl Default for RangeProducerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RangeProducerBuilder {
     pub fn new() -> RangeProducerBuilder {
         RangeProducerBuilder {
@@ -57,7 +65,7 @@ impl RangeProducer {
         let (event_tx, event_rx) = mpsc::unbounded::<ProducerEvent<Item>>();
 
         let inner_task = InnerTask::new(message_rx, event_tx, start, stop);
-        tokio::spawn(inner_task.map_err(|_| {}));
+        tokio::spawn(inner_task);
 
         RangeProducer {
             message_tx,
@@ -67,14 +75,15 @@ impl RangeProducer {
 }
 
 impl Streamer for RangeProducer {
-    fn cancel(&mut self, _reason: CancelReason) {
-        // TODO: implement cancel
+    fn cancel(&mut self, reason: CancelReason) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Cancel(reason));
     }
 }
 
 impl Producer<Item> for RangeProducer {
     fn request(&mut self, num_items: usize) {
-        match (&self.message_tx).unbounded_send(ProducerMessage::Request(num_items)) {
+        match self.message_tx.unbounded_send(ProducerMessage::Request(num_items)) {
             Ok(_) => {
             },
             Err(_e) => {
@@ -108,42 +117,40 @@ impl InnerTask {
 }
 
 impl Future for InnerTask {
-    type Item = ();
-    type Error = io::Error;
+    type Output = ();
 
-    fn poll(&mut self) -> Poll<(), io::Error> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
         loop {
-            match self.message_rx.poll() {
-                Ok(Async::Ready(Some(ProducerMessage::Request(num_items)))) => {
+            match self.message_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ProducerMessage::Request(num_items))) => {
                     self.demand += num_items;
 
                     while self.demand > 0 {
-                        (&self.event_tx).unbounded_send(ProducerEvent::Data(self.current_value)).unwrap();
+                        if self.event_tx.unbounded_send(ProducerEvent::Data(self.current_value)).is_err() {
+                            // nobody is listening anymore
+                            return Poll::Ready(());
+                        }
                         self.current_value += 1;
 
                         if let Some(stop_value) = self.stop { 
                             if self.current_value == stop_value {
-                                (&self.event_tx).unbounded_send(ProducerEvent::End).unwrap();
-                                return Ok(Async::Ready(()));
+                                let _ = self.event_tx.unbounded_send(ProducerEvent::End);
+                                return Poll::Ready(());
                             }
                         }
                         self.demand -= 1;
                     }
                 },
-                Ok(Async::Ready(Some(ProducerMessage::Cancel(_reason)))) => {
-                    // TODO: implement
-                    panic!("Cancel RangeProducer");
+                // Dropping the event sender lets downstream know we're done
+                Poll::Ready(Some(ProducerMessage::Cancel(_reason))) => {
+                    return Poll::Ready(());
                 },
-                Ok(Async::Ready(None)) => {
-                    return Ok(Async::Ready(()));
+                Poll::Ready(None) => {
+                    return Poll::Ready(());
                 },
-                Ok(Async::NotReady) => {
-                    return Ok(Async::NotReady);
+                Poll::Pending => {
+                    return Poll::Pending;
                 },
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                    return Ok(Async::Ready(()));
-                }
             }
         }
     }
@@ -154,33 +161,48 @@ impl Future for InnerTask {
 #[cfg(test)]
 mod tests {
 
-    use futures::future::lazy;
     use super::*;
 
-    #[test]
-    fn create() {
-        tokio::run(lazy(|| {
-            RangeProducer::new(0, None);
-
-            Ok(())
-        }));
+    #[tokio::test]
+    async fn create() {
+        RangeProducer::new(0, None);
     }
 
-    #[test]
-    fn simple() {
-        tokio::run(lazy(|| {
-            let mut producer = RangeProducer::new(1, None);
+    #[tokio::test]
+    async fn cancel() {
+        let mut producer = RangeProducer::new(0, None);
+
+        let mut events = producer.event_stream().unwrap();
 
-            let events = producer.event_stream().unwrap();
+        producer.request(2);
+        producer.cancel(CancelReason::Disconnected);
+        producer.request(2);
 
-            tokio::spawn(events.for_each(|_event| {
-                Ok(())
-            })
-            .map_err(|_| {}));
+        let mut received = 0;
 
-            producer.request(100);
+        while let Some(event) = events.next().await {
+            match event {
+                ProducerEvent::Data(_) => received += 1,
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
 
-            Ok(())
-        }));
+        assert_eq!(received, 2);
+    }
+
+    #[tokio::test]
+    async fn simple() {
+        let mut producer = RangeProducer::new(1, None);
+
+        let mut events = producer.event_stream().unwrap();
+
+        producer.request(100);
+
+        for i in 1..=100 {
+            match events.next().await {
+                Some(ProducerEvent::Data(value)) => assert_eq!(value, i),
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
     }
 }
@@ -1,15 +1,19 @@
-use tokio::io;
-use tokio::prelude::*;
-use futures::sync::mpsc;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, ReadBuf};
+use futures::channel::mpsc;
+use futures::StreamExt;
 use super::{
     Producer, ProducerEvent, ProducerEventRx, ProducerEventTx,
     ProducerMessage, ProducerMessageRx, ProducerMessageTx, Streamer,
-    CancelReason,
+    CancelReason, Error,
 };
 
 type Item = Vec<u8>;
 
-const CHUNK_SIZE: usize = 1024;
+const DEFAULT_CHUNK_SIZE: usize = 1024;
 
 #[derive(Debug)]
 pub struct ReadAdapter {
@@ -17,34 +21,61 @@ pub struct ReadAdapter {
     event_rx: Option<ProducerEventRx<Item>>,
 }
 
+pub struct ReadAdapterBuilder {
+    chunk_size: usize,
+}
+
 enum ReadAdapterState<T, U>
-    where T: Future<Item=U, Error=io::Error>,
-          U: AsyncRead,
+    where T: Future<Output=io::Result<U>>,
+          U: AsyncRead + Unpin,
 {
-    WaitingForReader(T),
+    WaitingForReader(Pin<Box<T>>),
     Reading(U),
 }
 
 struct InnerTask<T, U>
-    where T: Future<Item=U, Error=io::Error>,
-          U: AsyncRead,
+    where T: Future<Output=io::Result<U>>,
+          U: AsyncRead + Unpin,
 {
     state: ReadAdapterState<T, U>,
     message_rx: ProducerMessageRx,
     event_tx: ProducerEventTx<Item>,
     demand: usize,
+    // Reused until a read fills it, so a pending read doesn't allocate
+    buf: Vec<u8>,
+    chunk_size: usize,
 }
 
-impl ReadAdapter {
-    pub fn new<T, U>(reader_future: T) -> ReadAdapter
-        where T: Future<Item=U, Error=io::Error> + Send + 'static,
-              U: AsyncRead + Send + 'static,
+impl Default for ReadAdapterBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReadAdapterBuilder {
+    pub fn new() -> ReadAdapterBuilder {
+        ReadAdapterBuilder {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    /// Largest chunk emitted per requested item. Chunks can be shorter if
+    /// the reader has less available.
+    pub fn chunk_size(mut self, value: usize) -> ReadAdapterBuilder {
+        assert!(value > 0, "chunk size must be at least 1");
+        self.chunk_size = value;
+        self
+    }
+
+    pub fn build<T, U>(self, reader_future: T) -> ReadAdapter
+        where T: Future<Output=io::Result<U>> + Send + 'static,
+              U: AsyncRead + Unpin + Send + 'static,
     {
         let (message_tx, message_rx) = mpsc::unbounded::<ProducerMessage>();
         let (event_tx, event_rx) = mpsc::unbounded::<ProducerEvent<Item>>();
 
-        let inner_task = InnerTask::new(reader_future, message_rx, event_tx);
-        tokio::spawn(inner_task.map_err(|_| {}));
+        let inner_task = InnerTask::new(reader_future, message_rx, event_tx, self.chunk_size);
+        tokio::spawn(inner_task);
 
         ReadAdapter {
             message_tx,
@@ -53,120 +84,205 @@ impl ReadAdapter {
     }
 }
 
+impl ReadAdapter {
+    pub fn new<T, U>(reader_future: T) -> ReadAdapter
+        where T: Future<Output=io::Result<U>> + Send + 'static,
+              U: AsyncRead + Unpin + Send + 'static,
+    {
+        ReadAdapterBuilder::new().build(reader_future)
+    }
+}
+
 impl Streamer for ReadAdapter {
-    fn cancel(&mut self, _reason: CancelReason) {
-        // TODO: implement cancel
+    fn cancel(&mut self, reason: CancelReason) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Cancel(reason));
     }
 }
 
 impl Producer<Item> for ReadAdapter {
     fn request(&mut self, num_items: usize) {
-        match (&self.message_tx).unbounded_send(ProducerMessage::Request(num_items)) {
-            Ok(_) => {
-            },
-            Err(_e) => {
-                // TODO: properly handle
-                //eprintln!("{:?}", e);
-            }
-        }
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Request(num_items));
     }
 
     fn event_stream(&mut self) -> Option<ProducerEventRx<Item>> {
         Option::take(&mut self.event_rx)
     }
+
+    fn set_event_stream(&mut self, event_stream: ProducerEventRx<Item>) {
+        self.event_rx = Some(event_stream);
+    }
 }
 
 impl<T, U> InnerTask<T, U>
-    where T: Future<Item=U, Error=io::Error>,
-          U: AsyncRead,
+    where T: Future<Output=io::Result<U>>,
+          U: AsyncRead + Unpin,
 {
-    fn new(reader_future: T, message_rx: ProducerMessageRx, event_tx: ProducerEventTx<Item>) -> InnerTask<T, U> {
+    fn new(reader_future: T, message_rx: ProducerMessageRx, event_tx: ProducerEventTx<Item>,
+           chunk_size: usize) -> InnerTask<T, U> {
 
         InnerTask {
-            state: ReadAdapterState::WaitingForReader(reader_future),
+            state: ReadAdapterState::WaitingForReader(Box::pin(reader_future)),
             message_rx,
             event_tx,
             demand: 0,
+            buf: vec![0; chunk_size],
+            chunk_size,
         }
     }
+
+    fn fail(&self, e: io::Error) -> Poll<()> {
+        // Consumer may already be gone, in which case there's nobody to tell
+        let _ = self.event_tx.unbounded_send(ProducerEvent::Error(Error::Io(e)));
+        Poll::Ready(())
+    }
 }
 
 impl<T, U> Future for InnerTask<T, U>
-    where T: Future<Item=U, Error=io::Error>,
-          U: AsyncRead,
+    where T: Future<Output=io::Result<U>>,
+          U: AsyncRead + Unpin,
 {
-    type Item = ();
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<(), io::Error> {
-
-        match self.state {
-            ReadAdapterState::Reading(ref mut reader) => {
-
-                loop {
-                    match self.message_rx.poll().unwrap() {
-                        Async::Ready(Some(ProducerMessage::Request(num_items))) => {
-                            self.demand += num_items;
-                        },
-                        Async::Ready(Some(ProducerMessage::Cancel(_reason))) => {
-                            // TODO: implement
-                            panic!("Cancel ReadAdapter");
-                        },
-                        Async::Ready(None) => {
-                            return Ok(Async::Ready(()));
-                        },
-                        Async::NotReady => {
-                            break;
-                        },
-                    }
-                }
+    type Output = ();
 
-                while self.demand > 0 {
-                    let mut buf = [0; CHUNK_SIZE];
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
 
-                    match reader.poll_read(&mut buf) {
-                        Ok(Async::Ready(n)) => {
+        let this = self.get_mut();
 
-                            self.demand -= 1;
+        // Returning Ready drops the reader along with the task
+        loop {
+            match this.message_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ProducerMessage::Request(num_items))) => {
+                    this.demand += num_items;
+                },
+                Poll::Ready(Some(ProducerMessage::Cancel(_reason))) => {
+                    return Poll::Ready(());
+                },
+                Poll::Ready(None) => {
+                    return Poll::Ready(());
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
 
-                            (&self.event_tx).unbounded_send(ProducerEvent::Data(buf[0..n].to_vec())).unwrap();
+        if let ReadAdapterState::WaitingForReader(ref mut fut) = this.state {
+            match fut.as_mut().poll(cx) {
+                Poll::Ready(Ok(reader)) => {
+                    this.state = ReadAdapterState::Reading(reader);
+                },
+                Poll::Ready(Err(e)) => {
+                    return this.fail(e);
+                },
+                Poll::Pending => {
+                    return Poll::Pending;
+                },
+            }
+        }
 
-                            if n != CHUNK_SIZE {
-                                //eprintln!("wrong chunk size: {}", n);
-                            }
+        if let ReadAdapterState::Reading(ref mut reader) = this.state {
+            while this.demand > 0 {
+                let mut read_buf = ReadBuf::new(&mut this.buf);
 
-                            if n == 0 {
-                                return Ok(Async::Ready(()));
-                            }
-                        },
-                        Ok(Async::NotReady) => {
-                            return Ok(Async::NotReady);
-                        },
-                        Err(_e) => {
-                            break;
-                        },
-                    };
-                }
-            },
-            ReadAdapterState::WaitingForReader(ref mut fut) => {
-                return match fut.poll() {
-                    Ok(Async::Ready(reader)) => {
-                        self.state = ReadAdapterState::Reading(reader);
-                        task::current().notify();
-                        Ok(Async::NotReady)
+                match Pin::new(&mut *reader).poll_read(cx, &mut read_buf) {
+                    Poll::Ready(Ok(())) => {
+                        let n = read_buf.filled().len();
+
+                        if n == 0 {
+                            let _ = this.event_tx.unbounded_send(ProducerEvent::End);
+                            return Poll::Ready(());
+                        }
+
+                        // Hand the filled buffer off and start a fresh one
+                        let mut data = mem::replace(&mut this.buf, vec![0; this.chunk_size]);
+                        data.truncate(n);
+                        this.demand -= 1;
+
+                        if this.event_tx.unbounded_send(ProducerEvent::Data(data)).is_err() {
+                            // nobody is listening anymore
+                            return Poll::Ready(());
+                        }
+                    },
+                    Poll::Ready(Err(e)) => {
+                        return this.fail(e);
                     },
-                    Ok(Async::NotReady) => {
-                        Ok(Async::NotReady)
+                    Poll::Pending => {
+                        break;
                     },
-                    Err(e) => {
-                        Err(e)
-                    }
                 }
-            },
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+
+    fn reader(len: usize) -> impl Future<Output=io::Result<Cursor<Vec<u8>>>> {
+        futures::future::ok(Cursor::new((0..len).map(|i| i as u8).collect()))
+    }
+
+    #[tokio::test]
+    async fn chunks() {
+        let mut producer = ReadAdapterBuilder::new()
+            .chunk_size(1000)
+            .build(reader(2500));
+        let mut events = producer.event_stream().unwrap();
+
+        producer.request(10);
+
+        let mut received = Vec::new();
+
+        loop {
+            match events.next().await {
+                Some(ProducerEvent::Data(data)) => {
+                    assert!(!data.is_empty());
+                    received.push(data.len());
+                },
+                Some(ProducerEvent::End) => break,
+                other => panic!("unexpected event: {:?}", other),
+            }
         }
 
-        //(&self.event_tx).send(ProducerEvent::Data(vec![66,66,66]));
-        //Ok(Async::Ready(()))
-        Ok(Async::NotReady)
+        assert_eq!(received, vec![1000, 1000, 500]);
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let mut producer = ReadAdapter::new(reader(5000));
+        let mut events = producer.event_stream().unwrap();
+
+        producer.request(1);
+
+        match events.next().await {
+            Some(ProducerEvent::Data(data)) => assert_eq!(data.len(), DEFAULT_CHUNK_SIZE),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        producer.cancel(CancelReason::Other("done".to_string()));
+        producer.request(1);
+
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn open_error() {
+        let mut producer = ReadAdapter::new(tokio::fs::File::open("/nonexistent/omnistreams"));
+        let mut events = producer.event_stream().unwrap();
+
+        producer.request(1);
+
+        match events.next().await {
+            Some(ProducerEvent::Error(Error::Io(e))) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(events.next().await.is_none());
     }
 }
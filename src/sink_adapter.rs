@@ -1,9 +1,12 @@
 use std::fmt::Debug;
-use tokio::prelude::*;
-use futures::sync::mpsc;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc;
+use futures::{ready, Sink, SinkExt, StreamExt};
 use super::{
     Consumer, ConsumerMessage, ConsumerEvent, ConsumerEventRx, ConsumerEventTx, ConsumerMessageRx,
-    ConsumerMessageTx, CancelReason
+    ConsumerMessageTx, CancelReason, Error,
 };
 
 type Message = Vec<u8>;
@@ -24,7 +27,7 @@ pub struct SinkAdapter {
 //}
 
 struct InnerTask<S, E>
-    where S: Sink<SinkItem=Message, SinkError=E> + Send + 'static,
+    where S: Sink<Message, Error=E> + Unpin + Send + 'static,
           E: 'static + Debug,
 {
     sink: S,
@@ -32,17 +35,18 @@ struct InnerTask<S, E>
     event_tx: ConsumerEventTx,
     demand: usize,
     buffered: Option<Message>,
+    ended: bool,
 }
 
 impl<S, E> InnerTask<S, E>
-    where S: Sink<SinkItem=Message, SinkError=E> + Send + 'static,
+    where S: Sink<Message, Error=E> + Unpin + Send + 'static,
           E: 'static + Debug,
 {
     fn new(sink: S, message_rx: ConsumerMessageRx<Message>, event_tx: ConsumerEventTx) -> Self {
 
         let initial_demand = 1;
 
-        (&event_tx).unbounded_send(ConsumerEvent::Request(initial_demand)).unwrap();
+        let _ = event_tx.unbounded_send(ConsumerEvent::Request(initial_demand));
 
         Self {
             sink,
@@ -50,89 +54,123 @@ impl<S, E> InnerTask<S, E>
             event_tx,
             demand: initial_demand,
             buffered: None,
+            ended: false,
         }
     }
 
-    fn send_or_buffer(&mut self, data: Message) {
-        match self.sink.start_send(data) {
-            Ok(AsyncSink::Ready) => {
-                (&self.event_tx).unbounded_send(ConsumerEvent::Request(1)).unwrap();
+    // Tell upstream to stop. The sink is dropped without closing it once
+    // the task returns.
+    fn fail(&mut self) {
+        self.ended = true;
+        // Upstream may already be gone, in which case there's nobody to tell
+        let _ = self.event_tx.unbounded_send(ConsumerEvent::Cancellation(CancelReason::Disconnected));
+    }
+
+    // Close the sink once the stream has ended. If that fails the stream
+    // didn't make it, so upstream is told the same as for any other error.
+    fn close(&mut self, cx: &mut Context) -> Poll<()> {
+        if let Err(_e) = ready!(self.sink.poll_close_unpin(cx)) {
+            self.fail();
+        }
+
+        Poll::Ready(())
+    }
+
+    fn send_or_buffer(&mut self, cx: &mut Context, data: Message) {
+        match self.sink.poll_ready_unpin(cx) {
+            Poll::Ready(Ok(())) => {
+                match self.sink.start_send_unpin(data) {
+                    Ok(()) => {
+                        // Upstream may already be gone, the End will still come
+                        let _ = self.event_tx.unbounded_send(ConsumerEvent::Request(1));
+                    },
+                    Err(_e) => {
+                        self.fail();
+                    },
+                }
             },
-            Ok(AsyncSink::NotReady(data)) => {
-                self.buffered = Some(data);
+            Poll::Ready(Err(_e)) => {
+                self.fail();
             },
-            Err(_e) => {
-                (&self.event_tx).unbounded_send(ConsumerEvent::Cancellation(CancelReason::Disconnected)).unwrap();
+            Poll::Pending => {
+                self.buffered = Some(data);
             },
         }
     }
 }
 
 impl<S, E> Future for InnerTask<S, E>
-    where S: Sink<SinkItem=Message, SinkError=E> + Send + 'static,
+    where S: Sink<Message, Error=E> + Unpin + Send + 'static,
           E: 'static + Debug,
 {
-    type Item = ();
-    type Error = E;
+    type Output = ();
 
-    fn poll(&mut self) -> Poll<(), E> {
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
 
-        let buffered = Option::take(&mut self.buffered);
+        let this = self.get_mut();
 
-        match buffered {
-            Some(data) => {
-                match self.sink.poll_complete() {
-                    Ok(Async::Ready(())) => {
-                        self.send_or_buffer(data);
-                    },
-                    Ok(Async::NotReady) => {
-                    },
-                    Err(e) => {
-                        eprintln!("SinkAdapter poll err: {:?}", e);
-                    },
-                }
-            },
-            None => {
-            },
+        if this.ended {
+            return this.close(cx);
+        }
+
+        if let Some(data) = Option::take(&mut this.buffered) {
+            this.send_or_buffer(cx, data);
+
+            if this.ended {
+                return Poll::Ready(());
+            }
         }
 
         // Only process messages if there isn't already something in the
         // buffer. Otherwise there's nowhere to put them.
-        while self.buffered.is_none() {
-            match self.message_rx.poll().unwrap() {
-                Async::Ready(Some(ConsumerMessage::Write(data))) => {
-                    if self.demand == 0 {
+        while this.buffered.is_none() {
+            match this.message_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ConsumerMessage::Write(data))) => {
+                    if this.demand == 0 {
                         panic!("SinkAdapter: Attempt to write more than requested");
                     }
 
-                    self.send_or_buffer(data);
+                    this.send_or_buffer(cx, data);
+
+                    if this.ended {
+                        return Poll::Ready(());
+                    }
                 },
-                Async::Ready(Some(ConsumerMessage::End)) => {
-                    return Ok(Async::Ready(()));
+                Poll::Ready(Some(ConsumerMessage::End)) => {
+                    this.ended = true;
+                    return this.close(cx);
                 },
-                Async::Ready(None) => {
-                    return Ok(Async::Ready(()));
+                // Drop the sink without closing it, so the other end doesn't
+                // mistake what it got for the whole stream. A consumer dropped
+                // without ending didn't finish either.
+                Poll::Ready(Some(ConsumerMessage::Abort(_))) | Poll::Ready(None) => {
+                    return Poll::Ready(());
                 },
-                Async::NotReady => {
+                Poll::Pending => {
                     break;
                 },
             }
         }
 
-        Ok(Async::NotReady)
+        if let Poll::Ready(Err(_e)) = this.sink.poll_flush_unpin(cx) {
+            this.fail();
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
     }
 }
 
 impl SinkAdapter {
     pub fn new<S, E>(sink: S) -> Self
-        where S: Sink<SinkItem=Message, SinkError=E> + Send + 'static,
+        where S: Sink<Message, Error=E> + Unpin + Send + 'static,
               E: 'static + Debug,
     {
         let (message_tx, message_rx) = mpsc::unbounded::<ConsumerMessage<Message>>();
         let (event_tx, event_rx) = mpsc::unbounded::<ConsumerEvent>();
 
         let inner_task = InnerTask::new(sink, message_rx, event_tx);
-        tokio::spawn(inner_task.map_err(|_| {}));
+        tokio::spawn(inner_task);
 
         Self {
             message_tx,
@@ -143,13 +181,18 @@ impl SinkAdapter {
 
 impl Consumer<Message> for SinkAdapter {
     fn write(&self, data: Message) {
-        let tx = &self.message_tx;
-        tx.unbounded_send(ConsumerMessage::Write(data)).unwrap();
+        // Sink has already failed and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::Write(data));
     }
 
     fn end(&self) {
-        let tx = &self.message_tx;
-        tx.unbounded_send(ConsumerMessage::End).unwrap();
+        // Sink has already failed and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::End);
+    }
+
+    fn abort(&self, error: Error) {
+        // Sink has already failed and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::Abort(error));
     }
 
     fn event_stream(&mut self) -> Option<ConsumerEventRx> {
@@ -160,3 +203,143 @@ impl Consumer<Message> for SinkAdapter {
         self.event_rx = Some(event_stream);
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::task::Waker;
+    use crate::{MapConduit, Conduit, Producer, StreamProducer};
+
+    #[derive(Default)]
+    struct SinkState {
+        ready: bool,
+        waker: Option<Waker>,
+        items: Vec<Message>,
+        closed: bool,
+        close_error: bool,
+    }
+
+    // Isn't ready until the test says so, and fails to close if
+    // `close_error` is set
+    #[derive(Clone, Default)]
+    struct GatedSink {
+        state: Arc<Mutex<SinkState>>,
+    }
+
+    impl GatedSink {
+        fn open(&self) {
+            let mut state = self.state.lock().unwrap();
+            state.ready = true;
+
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl Sink<Message> for GatedSink {
+        type Error = ();
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), ()>> {
+            let mut state = self.state.lock().unwrap();
+
+            if state.ready {
+                Poll::Ready(Ok(()))
+            }
+            else {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), ()> {
+            self.state.lock().unwrap().items.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), ()>> {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+
+            if state.close_error {
+                Poll::Ready(Err(()))
+            }
+            else {
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_while_buffered() {
+        let sink = GatedSink::default();
+        let (mut input, producer) = MapConduit::new(|x: Message| x).split();
+        let mut input_events = input.event_stream().unwrap();
+
+        let mut handle = producer.pipe_into(SinkAdapter::new(sink.clone()));
+        assert_eq!(input_events.next().await, Some(ConsumerEvent::Request(1)));
+        input.write(vec![1]);
+
+        // wait for the item to get stuck in the adapter
+        while sink.state.lock().unwrap().waker.is_none() {
+            tokio::task::yield_now().await;
+        }
+
+        // the pipe drops the adapter's events along with the adapter
+        handle.abort();
+        assert!(matches!(handle.await, Err(Error::Cancelled(_))));
+
+        sink.open();
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while !sink.state.lock().unwrap().closed {
+                tokio::task::yield_now().await;
+            }
+        }).await.expect("sink wasn't closed");
+
+        assert_eq!(sink.state.lock().unwrap().items, vec![vec![1]]);
+    }
+
+    #[tokio::test]
+    async fn close_error_cancels() {
+        let sink = GatedSink::default();
+        sink.state.lock().unwrap().close_error = true;
+        sink.open();
+
+        let producer = StreamProducer::new(futures::stream::iter(vec![vec![1]]));
+
+        match producer.pipe_into(SinkAdapter::new(sink.clone())).await {
+            Err(Error::Cancelled(CancelReason::Disconnected)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert!(sink.state.lock().unwrap().closed);
+    }
+
+    #[tokio::test]
+    async fn drop_skips_close() {
+        let sink = GatedSink::default();
+        sink.open();
+
+        let mut consumer = SinkAdapter::new(sink.clone());
+        let mut events = consumer.event_stream().unwrap();
+
+        assert_eq!(events.next().await, Some(ConsumerEvent::Request(1)));
+        consumer.write(vec![1]);
+        drop(consumer);
+
+        // the task drops its events along with the sink once it's done
+        assert_eq!(events.next().await, Some(ConsumerEvent::Request(1)));
+        assert_eq!(events.next().await, None);
+
+        let state = sink.state.lock().unwrap();
+        assert_eq!(state.items, vec![vec![1]]);
+        assert!(!state.closed);
+    }
+}
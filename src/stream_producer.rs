@@ -0,0 +1,187 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+use super::{
+    Producer, ProducerEvent, ProducerEventRx, ProducerEventTx,
+    ProducerMessage, ProducerMessageRx, ProducerMessageTx,
+    Streamer, CancelReason,
+};
+
+
+/// A producer fed by any `Stream`. Items are only pulled from the stream
+/// while there is outstanding demand.
+#[derive(Debug)]
+pub struct StreamProducer<T> {
+    message_tx: ProducerMessageTx,
+    event_rx: Option<ProducerEventRx<T>>,
+}
+
+struct InnerTask<S>
+    where S: Stream,
+{
+    stream: Pin<Box<S>>,
+    message_rx: ProducerMessageRx,
+    event_tx: ProducerEventTx<S::Item>,
+    demand: usize,
+}
+
+impl<T> StreamProducer<T>
+    where T: Send + 'static,
+{
+    pub fn new<S>(stream: S) -> StreamProducer<T>
+        where S: Stream<Item=T> + Send + 'static,
+    {
+        let (message_tx, message_rx) = mpsc::unbounded::<ProducerMessage>();
+        let (event_tx, event_rx) = mpsc::unbounded::<ProducerEvent<T>>();
+
+        let inner_task = InnerTask {
+            stream: Box::pin(stream),
+            message_rx,
+            event_tx,
+            demand: 0,
+        };
+        tokio::spawn(inner_task);
+
+        StreamProducer {
+            message_tx,
+            event_rx: Some(event_rx),
+        }
+    }
+}
+
+impl<T> Streamer for StreamProducer<T> {
+    fn cancel(&mut self, reason: CancelReason) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Cancel(reason));
+    }
+}
+
+impl<T> Producer<T> for StreamProducer<T>
+    where T: Send + 'static,
+{
+    fn request(&mut self, num_items: usize) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Request(num_items));
+    }
+
+    fn event_stream(&mut self) -> Option<ProducerEventRx<T>> {
+        Option::take(&mut self.event_rx)
+    }
+
+    fn set_event_stream(&mut self, event_stream: ProducerEventRx<T>) {
+        self.event_rx = Some(event_stream);
+    }
+}
+
+impl<S> Future for InnerTask<S>
+    where S: Stream,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+
+        let this = self.get_mut();
+
+        // Returning Ready drops the stream along with the task
+        loop {
+            match this.message_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ProducerMessage::Request(num_items))) => {
+                    this.demand += num_items;
+                },
+                Poll::Ready(Some(ProducerMessage::Cancel(_reason))) => {
+                    return Poll::Ready(());
+                },
+                Poll::Ready(None) => {
+                    return Poll::Ready(());
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
+
+        while this.demand > 0 {
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    this.demand -= 1;
+
+                    if this.event_tx.unbounded_send(ProducerEvent::Data(item)).is_err() {
+                        // nobody is listening anymore
+                        return Poll::Ready(());
+                    }
+                },
+                Poll::Ready(None) => {
+                    let _ = this.event_tx.unbounded_send(ProducerEvent::End);
+                    return Poll::Ready(());
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures::channel::oneshot;
+    use futures::stream;
+
+    #[tokio::test]
+    async fn all_items_then_end() {
+        let producer = StreamProducer::new(stream::iter(vec![1, 2, 3]));
+
+        let items: Vec<i32> = producer.into_stream(2).collect().await;
+        assert_eq!(items, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn pulls_only_on_demand() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counter = pulled.clone();
+
+        let mut producer = StreamProducer::new(stream::iter(0..).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        let mut events = producer.event_stream().unwrap();
+
+        producer.request(3);
+
+        for i in 0..3 {
+            match events.next().await {
+                Some(ProducerEvent::Data(value)) => assert_eq!(value, i),
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+
+        tokio::task::yield_now().await;
+        assert_eq!(pulled.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn cancel_drops_stream() {
+        let (dropped_tx, dropped_rx) = oneshot::channel::<()>();
+
+        // the stream owns dropped_tx, so dropped_rx resolves once it's gone
+        let mut producer = StreamProducer::new(stream::pending::<i32>().map(move |x| {
+            let _ = &dropped_tx;
+            x
+        }));
+        let mut events = producer.event_stream().unwrap();
+
+        producer.request(1);
+        producer.cancel(CancelReason::Disconnected);
+
+        assert!(dropped_rx.await.is_err());
+        assert!(events.next().await.is_none());
+    }
+}
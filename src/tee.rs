@@ -0,0 +1,293 @@
+use std::time::Instant;
+use futures::channel::oneshot;
+use futures::{future, stream, StreamExt};
+use super::{
+    Producer, ProducerEvent, Consumer, ConsumerEvent, CancelReason, Error,
+    PipeHandle, PipeStats,
+};
+use super::producer::PIPE_ABORTED;
+
+
+/// Pipes one producer into several consumers, each getting a copy of every
+/// item. Items are only requested upstream once every branch has asked for
+/// them, so the slowest consumer sets the pace. A branch that cancels is
+/// detached and the rest carry on. Upstream is only cancelled once every
+/// branch has.
+pub struct Tee<T, P>
+    where T: Clone + Send + 'static,
+          P: Producer<T> + Send + 'static,
+{
+    producer: P,
+    branches: Vec<Box<dyn Consumer<T> + Send>>,
+}
+
+struct Branch<T> {
+    // None once the branch has cancelled
+    consumer: Option<Box<dyn Consumer<T> + Send>>,
+    demand: usize,
+}
+
+impl<T, P> Tee<T, P>
+    where T: Clone + Send + 'static,
+          P: Producer<T> + Send + 'static,
+{
+    pub fn new(producer: P) -> Tee<T, P> {
+        Tee {
+            producer,
+            branches: Vec::new(),
+        }
+    }
+
+    pub fn branch<C>(mut self, consumer: C) -> Tee<T, P>
+        where C: Consumer<T> + Send + 'static,
+    {
+        self.branches.push(Box::new(consumer));
+        self
+    }
+
+    /// Start piping. Like [`pipe_into`](crate::pipe_into), the handle
+    /// succeeds once the producer has ended and every remaining branch is
+    /// done with the data. It fails if the producer fails, or with the last
+    /// cancellation if every branch cancels.
+    pub fn start(self) -> PipeHandle {
+        assert!(!self.branches.is_empty(), "tee needs at least one branch");
+
+        let mut producer = self.producer;
+        let mut producer_events = producer.event_stream().expect("no event stream");
+
+        let mut branches = Vec::new();
+        let mut events = Vec::new();
+
+        for (index, mut consumer) in self.branches.into_iter().enumerate() {
+            let consumer_events = consumer.event_stream().expect("no event stream");
+
+            // None marks the end of this branch's events
+            events.push(consumer_events
+                .map(Some)
+                .chain(stream::once(future::ready(None)))
+                .map(move |event| (index, event)));
+
+            branches.push(Branch {
+                consumer: Some(consumer),
+                demand: 0,
+            });
+        }
+
+        let mut branch_events = stream::select_all(events);
+        let (abort_tx, mut abort_rx) = oneshot::channel::<()>();
+
+        let task = tokio::spawn(async move {
+            let start = Instant::now();
+            let mut items = 0;
+            // requested upstream but not received yet
+            let mut requested = 0;
+            let mut ended = false;
+            let mut handle_dropped = false;
+
+            loop {
+                tokio::select! {
+                    event = producer_events.next(), if !ended => {
+                        match event {
+                            Some(ProducerEvent::Data(data)) => {
+                                items += 1;
+                                requested -= 1;
+
+                                for branch in branches.iter_mut() {
+                                    if let Some(ref consumer) = branch.consumer {
+                                        branch.demand -= 1;
+                                        consumer.write(data.clone());
+                                    }
+                                }
+                            },
+                            Some(ProducerEvent::End) => {
+                                for consumer in active(&branches) {
+                                    consumer.end();
+                                }
+                                ended = true;
+                            },
+                            Some(ProducerEvent::Error(e)) => {
+                                for consumer in active(&branches) {
+                                    consumer.abort(e.clone());
+                                }
+                                return Err(e);
+                            },
+                            None => {
+                                for consumer in active(&branches) {
+                                    consumer.abort(Error::Disconnected);
+                                }
+                                return Err(Error::Disconnected);
+                            },
+                        }
+                    },
+                    event = branch_events.next() => {
+                        let (index, event) = match event {
+                            Some(event) => event,
+                            // every branch is done with its events
+                            None => {
+                                return Ok(PipeStats {
+                                    items,
+                                    duration: start.elapsed(),
+                                });
+                            },
+                        };
+
+                        let branch = &mut branches[index];
+
+                        if branch.consumer.is_none() {
+                            continue;
+                        }
+
+                        let reason = match event {
+                            Some(ConsumerEvent::Request(num_items)) => {
+                                branch.demand += num_items;
+
+                                if !ended {
+                                    request_min(&mut producer, &branches, &mut requested);
+                                }
+                                continue;
+                            },
+                            Some(ConsumerEvent::Cancellation(reason)) => reason,
+                            // Done with the data, which is only fine once it ended
+                            None if ended => continue,
+                            None => CancelReason::Disconnected,
+                        };
+
+                        branch.consumer = None;
+
+                        if active(&branches).next().is_none() {
+                            if !ended {
+                                producer.cancel(reason.clone());
+                            }
+                            return Err(Error::Cancelled(reason));
+                        }
+
+                        // the detached branch may have been the slowest
+                        if !ended {
+                            request_min(&mut producer, &branches, &mut requested);
+                        }
+                    },
+                    result = &mut abort_rx, if !handle_dropped => {
+                        match result {
+                            Ok(()) => {
+                                let reason = CancelReason::Other(PIPE_ABORTED.to_string());
+                                if !ended {
+                                    producer.cancel(reason.clone());
+                                    for consumer in active(&branches) {
+                                        consumer.end();
+                                    }
+                                }
+                                return Err(Error::Cancelled(reason));
+                            },
+                            Err(_) => {
+                                handle_dropped = true;
+                            },
+                        }
+                    },
+                }
+            }
+        });
+
+        PipeHandle::new(task, abort_tx)
+    }
+}
+
+fn active<T>(branches: &[Branch<T>]) -> impl Iterator<Item=&Box<dyn Consumer<T> + Send>> {
+    branches.iter().filter_map(|branch| branch.consumer.as_ref())
+}
+
+// Top up what's requested upstream to what every remaining branch can take.
+fn request_min<T, P>(producer: &mut P, branches: &[Branch<T>], requested: &mut usize)
+    where T: Send + 'static,
+          P: Producer<T>,
+{
+    let min_demand = branches.iter()
+        .filter(|branch| branch.consumer.is_some())
+        .map(|branch| branch.demand)
+        .min()
+        .unwrap_or(0);
+
+    if min_demand > *requested {
+        producer.request(min_demand - *requested);
+        *requested = min_demand;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{RangeProducer, MapConduit, Conduit, Streamer};
+
+    #[tokio::test]
+    async fn every_branch_gets_every_item() {
+        let (a, a_out) = MapConduit::new(|x: i64| x).split();
+        let (b, b_out) = MapConduit::new(|x: i64| x * 10).split();
+
+        let handle = Tee::new(RangeProducer::new(0, Some(5)))
+            .branch(a)
+            .branch(b)
+            .start();
+
+        let a_items = tokio::spawn(a_out.into_stream(1).collect::<Vec<i64>>());
+        let b_items = tokio::spawn(b_out.into_stream(3).collect::<Vec<i64>>());
+
+        assert_eq!(a_items.await.unwrap(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(b_items.await.unwrap(), vec![0, 10, 20, 30, 40]);
+        assert_eq!(handle.await.unwrap().items, 5);
+    }
+
+    #[tokio::test]
+    async fn requests_min_demand() {
+        let (mut source, source_out) = MapConduit::new(|x: i32| x).split();
+        let mut source_events = source.event_stream().unwrap();
+
+        let (a, mut a_out) = MapConduit::new(|x: i32| x).split();
+        let (b, mut b_out) = MapConduit::new(|x: i32| x).split();
+
+        let _handle = Tee::new(source_out).branch(a).branch(b).start();
+
+        a_out.request(3);
+        b_out.request(1);
+        assert_eq!(source_events.next().await, Some(ConsumerEvent::Request(1)));
+
+        b_out.request(4);
+        assert_eq!(source_events.next().await, Some(ConsumerEvent::Request(2)));
+    }
+
+    #[tokio::test]
+    async fn cancelled_branch_is_detached() {
+        let (a, a_out) = MapConduit::new(|x: i64| x).split();
+        let (b, mut b_out) = MapConduit::new(|x: i64| x).split();
+
+        let handle = Tee::new(RangeProducer::new(0, Some(5)))
+            .branch(a)
+            .branch(b)
+            .start();
+
+        b_out.cancel(CancelReason::Other("not interested".to_string()));
+
+        let a_items: Vec<i64> = a_out.into_stream(2).collect().await;
+        assert_eq!(a_items, vec![0, 1, 2, 3, 4]);
+        assert_eq!(handle.await.unwrap().items, 5);
+    }
+
+    #[tokio::test]
+    async fn all_branches_cancelled() {
+        let (a, mut a_out) = MapConduit::new(|x: i64| x).split();
+        let (b, mut b_out) = MapConduit::new(|x: i64| x).split();
+
+        let handle = Tee::new(RangeProducer::new(0, None))
+            .branch(a)
+            .branch(b)
+            .start();
+
+        a_out.cancel(CancelReason::Disconnected);
+        b_out.cancel(CancelReason::Other("done".to_string()));
+
+        match handle.await {
+            Err(Error::Cancelled(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
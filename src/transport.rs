@@ -1,210 +1,876 @@
-use futures::sync::mpsc;
-//use websocket::r#async::Server;
-//use websocket::server::InvalidConnection;
-//use websocket::message::{OwnedMessage};
-//use websocket::result::WebSocketError;
-//use tokio::reactor::Handle;
-//use futures::stream::{Stream};
-//use futures::sink::{Sink};
-//use futures::future::{Future};
-//use std::fmt::Debug;
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{self, Message as WsMessage};
+use std::fmt;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 type Message = Vec<u8>;
-type MessageRx = mpsc::UnboundedReceiver<Message>;
-//type MessageTx = mpsc::UnboundedSender<Message>;
-//type WebSocketTransportRx = mpsc::UnboundedReceiver<WebSocketTransport>;
+type MessageTx = mpsc::Sender<Message>;
+type IncomingTx = mpsc::UnboundedSender<Result<Message, TransportError>>;
 
+/// Incoming messages. If the connection breaks, the last item is the error
+/// that broke it. A clean close just ends the stream.
+pub type MessageRx = Pin<Box<dyn Stream<Item = Result<Message, TransportError>> + Send>>;
 
+// Anything bigger than this from the other end is treated as a broken
+// connection rather than allocated.
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+// How many outgoing messages the socket transports queue up for their writer
+// task before reporting that they're not ready.
+const OUT_BUFFER_SIZE: usize = 16;
+
+// How long the acceptors wait after a failed accept. Errors like running out
+// of file descriptors don't clear up straight away, and retrying at once
+// would just spin.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+
+/// A message based connection.
+///
+/// Sending works like a `Sink`: wait for `poll_ready` to return
+/// `Ready(Ok(()))`, then `send` one message. A transport that isn't ready is
+/// saturated, and callers are expected to hold off rather than queue more.
 pub trait Transport {
-    fn send(&mut self, message: Message);
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), TransportError>>;
+    fn send(&mut self, message: Message) -> Result<(), TransportError>;
+    /// Stop sending. Messages already sent are still delivered.
+    fn close(&mut self);
     fn messages(&mut self) -> Option<MessageRx>;
 }
 
-//pub trait Acceptor {
-//    fn transports(&mut self) -> Option<WebSocketTransportRx>;
-//}
-//
-//pub struct WebSocketTransport {
-//    in_rx: Option<MessageRx>,
-//    out_tx: MessageTx,
-//}
-//
-//pub struct WebSocketAcceptor {
-//    stream: Option<WebSocketTransportRx>,
-//}
-//
-//pub struct WebSocketAcceptorBuilder {
-//    host: String,
-//    port: u16,
-//}
-//
-////pub struct WebSocketInitiator {
-////}
-//
-//impl WebSocketTransport {
-//    //pub fn new(rx: MessageRx, sink: SplitSink<OwnedMessage>) -> WebSocketTransport {
-//    //    WebSocketTransport {
-//    //        sink,
-//    //        message_rx: Some(rx),
-//    //    }
-//    //}
-//}
-//
-//impl Transport for WebSocketTransport {
-//
-//    fn send(&mut self, message: Message) {
-//        self.out_tx.unbounded_send(message).expect("ws transport send");
-//    }
-//
-//    fn messages(&mut self) -> Option<MessageRx> {
-//        Option::take(&mut self.in_rx)
-//    }
-//}
-//
-//impl Acceptor for WebSocketAcceptor {
-//    fn transports(&mut self) -> Option<WebSocketTransportRx> {
-//        Option::take(&mut self.stream)
-//    }
-//}
-//
-//
-//impl WebSocketAcceptorBuilder {
-//    pub fn new() -> WebSocketAcceptorBuilder {
-//
-//        WebSocketAcceptorBuilder {
-//            host: "127.0.0.1".to_string(),
-//            port: 8080,
-//        }
-//    }
-//
-//    pub fn host(mut self, value: &str) -> WebSocketAcceptorBuilder {
-//        self.host = value.to_string();
-//        self
-//    }
-//
-//    pub fn port(mut self, value: u16) -> WebSocketAcceptorBuilder {
-//        self.port = value;
-//        self
-//    }
-//
-//    pub fn build(self) -> WebSocketAcceptor {
-//        let addr = format!("{}:{}", self.host, self.port);
-//        println!("{}", addr);
-//
-//        let (tx, rx) = mpsc::unbounded::<WebSocketTransport>();
-//
-//        let server = Server::bind(addr, &Handle::default()).expect("ws server bind");
-//
-//        let f = server
-//            .incoming()
-//            // we don't wanna save the stream if it drops
-//            .map_err(|InvalidConnection { error, .. }| error)
-//            .for_each(move |(upgrade, addr)| {
-//                println!("Got a connection from: {}", addr);
-//
-//                let tx = tx.clone();
-//
-//                // accept the request to be a ws connection if it does
-//                let f = upgrade
-//                    .accept()
-//                    .and_then(move |(s, _)| {
-//                        let (sink, stream) = s.split();
-//                        let (in_tx, in_rx) = mpsc::unbounded::<Message>();
-//                        let (out_tx, out_rx) = mpsc::unbounded::<Message>();
-//
-//                        let sink_f = sink.send_all(out_rx.map_err(|_e| {
-//                            WebSocketError::NoDataAvailable
-//                        })
-//                        .map(|m| {
-//                            OwnedMessage::Binary(m)
-//                        }));
-//
-//                        spawn_future(sink_f, "Sink Fut");
-//
-//                        let transport = WebSocketTransport {
-//                            out_tx,
-//                            in_rx: Some(in_rx),
-//                        };
-//
-//                        tx.unbounded_send(transport).expect("send transport");
-//
-//                        stream
-//                            .take_while(|m| Ok(!m.is_close()))
-//                            .filter_map(|m| {
-//                                match m {
-//                                    OwnedMessage::Ping(_p) => {
-//                                        //Some(OwnedMessage::Pong(p))
-//                                        None
-//                                    },
-//                                    OwnedMessage::Pong(_) => None,
-//                                    OwnedMessage::Binary(bm) => {
-//                                        Some(bm)
-//                                    },
-//                                    _ => {
-//                                        //Some(m)
-//                                        None
-//                                    },
-//                                }
-//                            })
-//                            // TODO: This should be possible using Stream.forward
-//                            //.forward(in_tx)
-//                            .for_each(move |m| {
-//                                in_tx.unbounded_send(m).expect("send");
-//                                Ok(())
-//                            })
-//                            // TODO: properly close
-//                            //.and_then(|(_, sink)| sink.send(OwnedMessage::Close(None)))
-//                    });
-//
-//                spawn_future(f, "Client Status");
-//
-//                Ok(())
-//        });
-//
-//        spawn_future(f, "Server fut");
-//
-//        WebSocketAcceptor {
-//            stream: Some(rx),
-//        }
-//    }
-//}
-//
-//fn spawn_future<F, I, E>(f: F, desc: &'static str)
-//where
-//	F: Future<Item = I, Error = E> + 'static + Send,
-//	E: Debug,
-//{
-//	tokio::spawn(
-//		f.map_err(move |e| println!("{}: '{:?}'", desc, e))
-//			.map(move |_| println!("{}: Finished.", desc)),
-//	);
-//}
-//
-////impl WebSocketInitiator {
-////    pub fn new() -> WebSocketInitiator {
-////        WebSocketInitiator {
-////        }
-////    }
-////}
-//
-//#[cfg(test)]
-//mod tests {
-//
-//    //use futures::future::lazy;
-//    //use super::*;
-//
-//    //#[test]
-//    //fn create() {
-//    //    WebSocketTransport::new();
-//    //}
-//    //
-//    //#[test]
-//    //fn create_ws_acceptor() {
-//    //    WebSocketAcceptor::new();
-//    //}
-//
-//    //#[test]
-//    //fn create_ws_initiator() {
-//    //    WebSocketInitiator::new();
-//    //}
-//}
+#[derive(PartialEq, Clone, Debug)]
+pub enum TransportError {
+    /// Closed by either side.
+    Closed,
+    /// `send` was called without waiting for `poll_ready`, and there was no
+    /// room for the message.
+    Full,
+    /// The underlying connection broke.
+    Failed(String),
+}
+
+pub trait Acceptor {
+    type Transport: Transport;
+
+    fn transports(&mut self) -> Option<mpsc::UnboundedReceiver<Self::Transport>>;
+}
+
+pub struct WebSocketTransport {
+    in_rx: Option<MessageRx>,
+    out_tx: MessageTx,
+}
+
+pub struct WebSocketAcceptor {
+    stream: Option<mpsc::UnboundedReceiver<WebSocketTransport>>,
+    local_addr: SocketAddr,
+}
+
+pub struct WebSocketAcceptorBuilder {
+    host: String,
+    port: u16,
+}
+
+pub struct WebSocketConnector {
+    url: String,
+}
+
+pub struct FramedTransport {
+    in_rx: Option<MessageRx>,
+    out_tx: MessageTx,
+}
+
+pub struct TcpAcceptor {
+    stream: Option<mpsc::UnboundedReceiver<FramedTransport>>,
+    local_addr: SocketAddr,
+}
+
+#[cfg(unix)]
+pub struct UnixAcceptor {
+    stream: Option<mpsc::UnboundedReceiver<FramedTransport>>,
+}
+
+/// One end of an in-memory connection, see [`pair`].
+pub struct MemoryTransport {
+    out_tx: Option<MemorySender>,
+    in_rx: Option<MessageRx>,
+    close_tx: Option<oneshot::Sender<()>>,
+}
+
+enum MemorySender {
+    Unbounded(mpsc::UnboundedSender<Message>),
+    Bounded(mpsc::Sender<Message>),
+}
+
+impl WebSocketTransport {
+    /// Wrap an already established WebSocket connection. Binary messages
+    /// are passed through as-is, pings are answered and text messages are
+    /// ignored. The incoming message stream ends when the peer closes the
+    /// connection or it fails.
+    pub fn new<S>(socket: WebSocketStream<S>) -> WebSocketTransport
+        where S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (mut sink, mut stream) = socket.split();
+        let (in_tx, in_rx) = mpsc::unbounded::<Result<Message, TransportError>>();
+        let (out_tx, mut out_rx) = mpsc::channel::<Message>(OUT_BUFFER_SIZE);
+        let failed_tx = in_tx.clone();
+
+        tokio::spawn(async move {
+            while let Some(message) = out_rx.next().await {
+                if let Err(e) = sink.send(WsMessage::Binary(message)).await {
+                    fail(&failed_tx, e.to_string());
+                    return;
+                }
+            }
+
+            // The transport was closed or dropped, so say goodbye properly
+            let _ = sink.close().await;
+        });
+
+        tokio::spawn(async move {
+            while let Some(message) = stream.next().await {
+                match message {
+                    Ok(WsMessage::Binary(message)) => {
+                        if in_tx.unbounded_send(Ok(message)).is_err() {
+                            break;
+                        }
+                    },
+                    Ok(WsMessage::Close(_)) => {
+                        break;
+                    },
+                    // tungstenite queues the pong reply on its own
+                    Ok(WsMessage::Ping(_)) | Ok(WsMessage::Pong(_)) => {
+                    },
+                    Ok(WsMessage::Text(_)) | Ok(WsMessage::Frame(_)) => {
+                    },
+                    Err(tungstenite::Error::ConnectionClosed) => {
+                        break;
+                    },
+                    Err(e) => {
+                        fail(&in_tx, e.to_string());
+                        break;
+                    },
+                }
+            }
+
+            in_tx.close_channel();
+        });
+
+        WebSocketTransport {
+            in_rx: Some(in_rx.boxed()),
+            out_tx,
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), TransportError>> {
+        self.out_tx.poll_ready(cx).map_err(|_| TransportError::Closed)
+    }
+
+    fn send(&mut self, message: Message) -> Result<(), TransportError> {
+        self.out_tx.try_send(message).map_err(send_error)
+    }
+
+    fn close(&mut self) {
+        self.out_tx.close_channel();
+    }
+
+    fn messages(&mut self) -> Option<MessageRx> {
+        Option::take(&mut self.in_rx)
+    }
+}
+
+impl WebSocketAcceptor {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Acceptor for WebSocketAcceptor {
+    type Transport = WebSocketTransport;
+
+    fn transports(&mut self) -> Option<mpsc::UnboundedReceiver<WebSocketTransport>> {
+        Option::take(&mut self.stream)
+    }
+}
+
+
+impl Default for WebSocketAcceptorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebSocketAcceptorBuilder {
+    pub fn new() -> WebSocketAcceptorBuilder {
+
+        WebSocketAcceptorBuilder {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+        }
+    }
+
+    pub fn host(mut self, value: &str) -> WebSocketAcceptorBuilder {
+        self.host = value.to_string();
+        self
+    }
+
+    pub fn port(mut self, value: u16) -> WebSocketAcceptorBuilder {
+        self.port = value;
+        self
+    }
+
+    pub async fn build(self) -> io::Result<WebSocketAcceptor> {
+        let addr = format!("{}:{}", self.host, self.port);
+
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let (tx, rx) = mpsc::unbounded::<WebSocketTransport>();
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(_) => {
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    },
+                };
+
+                if tx.is_closed() {
+                    // nobody is listening for new transports anymore
+                    return;
+                }
+
+                let tx = tx.clone();
+
+                // A failed handshake only affects that one client, which is
+                // just dropped
+                tokio::spawn(async move {
+                    if let Ok(socket) = tokio_tungstenite::accept_async(socket).await {
+                        let _ = tx.unbounded_send(WebSocketTransport::new(socket));
+                    }
+                });
+            }
+        });
+
+        Ok(WebSocketAcceptor {
+            stream: Some(rx),
+            local_addr,
+        })
+    }
+}
+
+impl WebSocketConnector {
+    pub fn new(url: &str) -> WebSocketConnector {
+        WebSocketConnector {
+            url: url.to_string(),
+        }
+    }
+
+    pub async fn connect(self) -> Result<WebSocketTransport, tungstenite::Error> {
+        let (socket, _response) = tokio_tungstenite::connect_async(self.url.as_str()).await?;
+        Ok(WebSocketTransport::new(socket))
+    }
+}
+
+impl FramedTransport {
+    /// Send messages over any byte stream. Each message goes on the wire as
+    /// a 4 byte big-endian length followed by the message itself. The
+    /// incoming message stream ends on EOF. An I/O error, or the peer
+    /// announcing a message larger than 16 MiB, fails the transport.
+    pub fn new<S>(stream: S) -> FramedTransport
+        where S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = io::split(stream);
+        let (in_tx, in_rx) = mpsc::unbounded::<Result<Message, TransportError>>();
+        let (out_tx, mut out_rx) = mpsc::channel::<Message>(OUT_BUFFER_SIZE);
+        let failed_tx = in_tx.clone();
+
+        tokio::spawn(async move {
+            while let Some(message) = out_rx.next().await {
+                let mut frame = Vec::with_capacity(4 + message.len());
+                frame.extend(&(message.len() as u32).to_be_bytes());
+                frame.extend(message);
+
+                if let Err(e) = writer.write_all(&frame).await {
+                    fail(&failed_tx, e.to_string());
+                    return;
+                }
+            }
+
+            // The transport was closed or dropped, so let the other end see EOF
+            let _ = writer.shutdown().await;
+        });
+
+        tokio::spawn(async move {
+            loop {
+                let header = match read_header(&mut reader).await {
+                    Ok(Some(header)) => header,
+                    // clean EOF between frames
+                    Ok(None) => break,
+                    Err(e) => {
+                        fail(&in_tx, e.to_string());
+                        break;
+                    },
+                };
+
+                let len = u32::from_be_bytes(header) as usize;
+
+                if len > MAX_FRAME_LENGTH {
+                    fail(&in_tx, format!("message too large: {}", len));
+                    break;
+                }
+
+                let mut message = vec![0; len];
+
+                if let Err(e) = reader.read_exact(&mut message).await {
+                    fail(&in_tx, e.to_string());
+                    break;
+                }
+
+                if in_tx.unbounded_send(Ok(message)).is_err() {
+                    break;
+                }
+            }
+
+            in_tx.close_channel();
+        });
+
+        FramedTransport {
+            in_rx: Some(in_rx.boxed()),
+            out_tx,
+        }
+    }
+}
+
+impl Transport for FramedTransport {
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), TransportError>> {
+        self.out_tx.poll_ready(cx).map_err(|_| TransportError::Closed)
+    }
+
+    fn send(&mut self, message: Message) -> Result<(), TransportError> {
+        // The peer would treat this as a broken connection anyway
+        if message.len() > MAX_FRAME_LENGTH {
+            return Err(TransportError::Failed(format!("message too large: {}", message.len())));
+        }
+
+        self.out_tx.try_send(message).map_err(send_error)
+    }
+
+    fn close(&mut self) {
+        self.out_tx.close_channel();
+    }
+
+    fn messages(&mut self) -> Option<MessageRx> {
+        Option::take(&mut self.in_rx)
+    }
+}
+
+impl TcpAcceptor {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpAcceptor> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let (tx, rx) = mpsc::unbounded::<FramedTransport>();
+
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, _addr)) => {
+                        if tx.unbounded_send(FramedTransport::new(socket)).is_err() {
+                            // nobody is listening for new transports anymore
+                            return;
+                        }
+                    },
+                    Err(_) => {
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    },
+                }
+            }
+        });
+
+        Ok(TcpAcceptor {
+            stream: Some(rx),
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Acceptor for TcpAcceptor {
+    type Transport = FramedTransport;
+
+    fn transports(&mut self) -> Option<mpsc::UnboundedReceiver<FramedTransport>> {
+        Option::take(&mut self.stream)
+    }
+}
+
+pub async fn tcp_connect<A: ToSocketAddrs>(addr: A) -> io::Result<FramedTransport> {
+    let socket = TcpStream::connect(addr).await?;
+    socket.set_nodelay(true)?;
+    Ok(FramedTransport::new(socket))
+}
+
+#[cfg(unix)]
+impl UnixAcceptor {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixAcceptor> {
+        let listener = UnixListener::bind(path)?;
+
+        let (tx, rx) = mpsc::unbounded::<FramedTransport>();
+
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, _addr)) => {
+                        if tx.unbounded_send(FramedTransport::new(socket)).is_err() {
+                            // nobody is listening for new transports anymore
+                            return;
+                        }
+                    },
+                    Err(_) => {
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    },
+                }
+            }
+        });
+
+        Ok(UnixAcceptor {
+            stream: Some(rx),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Acceptor for UnixAcceptor {
+    type Transport = FramedTransport;
+
+    fn transports(&mut self) -> Option<mpsc::UnboundedReceiver<FramedTransport>> {
+        Option::take(&mut self.stream)
+    }
+}
+
+#[cfg(unix)]
+pub async fn unix_connect<P: AsRef<Path>>(path: P) -> io::Result<FramedTransport> {
+    let socket = UnixStream::connect(path).await?;
+    Ok(FramedTransport::new(socket))
+}
+
+/// Create two transports connected to each other in memory. Whatever one
+/// sends, the other receives.
+pub fn pair() -> (MemoryTransport, MemoryTransport) {
+    let (a_tx, b_rx) = mpsc::unbounded::<Message>();
+    let (b_tx, a_rx) = mpsc::unbounded::<Message>();
+
+    (MemoryTransport::new(MemorySender::Unbounded(a_tx), a_rx),
+     MemoryTransport::new(MemorySender::Unbounded(b_tx), b_rx))
+}
+
+/// Like [`pair`], but at most `capacity` messages can be waiting to be
+/// received in each direction. Past that, `poll_ready` returns `Pending`
+/// until the peer catches up.
+pub fn pair_with_capacity(capacity: usize) -> (MemoryTransport, MemoryTransport) {
+    assert!(capacity > 0, "capacity must be at least 1");
+
+    // every sender gets one guaranteed slot on top of the buffer
+    let (a_tx, b_rx) = mpsc::channel::<Message>(capacity - 1);
+    let (b_tx, a_rx) = mpsc::channel::<Message>(capacity - 1);
+
+    (MemoryTransport::new(MemorySender::Bounded(a_tx), a_rx),
+     MemoryTransport::new(MemorySender::Bounded(b_tx), b_rx))
+}
+
+impl MemoryTransport {
+    fn new<S>(out_tx: MemorySender, in_rx: S) -> MemoryTransport
+        where S: Stream<Item = Message> + Send + 'static,
+    {
+        let (close_tx, close_rx) = oneshot::channel();
+
+        MemoryTransport {
+            out_tx: Some(out_tx),
+            // Closing (or dropping) this end also ends its own incoming
+            // messages, not just the peer's.
+            in_rx: Some(in_rx.map(Ok).take_until(close_rx).boxed()),
+            close_tx: Some(close_tx),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), TransportError>> {
+        let result = match self.out_tx {
+            Some(MemorySender::Unbounded(ref tx)) => tx.poll_ready(cx),
+            Some(MemorySender::Bounded(ref mut tx)) => tx.poll_ready(cx),
+            None => return Poll::Ready(Err(TransportError::Closed)),
+        };
+
+        result.map_err(|_| TransportError::Closed)
+    }
+
+    fn send(&mut self, message: Message) -> Result<(), TransportError> {
+        match self.out_tx {
+            Some(MemorySender::Unbounded(ref tx)) => tx.unbounded_send(message).map_err(send_error),
+            Some(MemorySender::Bounded(ref mut tx)) => tx.try_send(message).map_err(send_error),
+            None => Err(TransportError::Closed),
+        }
+    }
+
+    /// Close both directions. The peer's messages end once it has received
+    /// everything sent before this, and anything it sends afterwards is
+    /// dropped.
+    fn close(&mut self) {
+        self.out_tx = None;
+        self.close_tx = None;
+    }
+
+    fn messages(&mut self) -> Option<MessageRx> {
+        Option::take(&mut self.in_rx)
+    }
+}
+
+// Read a frame's length header, or None if the stream ended cleanly before
+// it. Ending partway through the header is as much an error as ending
+// partway through the body.
+async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<[u8; 4]>> {
+    let mut header = [0; 4];
+    let mut filled = 0;
+
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+
+    Ok(Some(header))
+}
+
+// Report why the connection broke, then end the incoming messages even if
+// the other direction is still holding a sender.
+fn fail(in_tx: &IncomingTx, reason: String) {
+    let _ = in_tx.unbounded_send(Err(TransportError::Failed(reason)));
+    in_tx.close_channel();
+}
+
+fn send_error(e: mpsc::TrySendError<Message>) -> TransportError {
+    if e.is_full() {
+        TransportError::Full
+    }
+    else {
+        TransportError::Closed
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Closed => write!(f, "transport closed"),
+            TransportError::Full => write!(f, "transport not ready"),
+            TransportError::Failed(reason) => write!(f, "transport failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use futures::FutureExt;
+    use futures::future::poll_fn;
+    use crate::{
+        Multiplexer, MultiplexerEvent, EventEmitter, Consumer, Producer, ProducerEvent,
+    };
+
+    async fn connected_pair() -> (WebSocketTransport, WebSocketTransport) {
+        let mut acceptor = WebSocketAcceptorBuilder::new()
+            .port(0)
+            .build()
+            .await
+            .unwrap();
+
+        let mut transports = acceptor.transports().unwrap();
+
+        let url = format!("ws://{}", acceptor.local_addr());
+        let client = WebSocketConnector::new(&url).connect().await.unwrap();
+        let server = transports.next().await.unwrap();
+
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn loopback() {
+        let (mut server, mut client) = connected_pair().await;
+
+        let mut server_messages = server.messages().unwrap();
+        let mut client_messages = client.messages().unwrap();
+
+        client.send(vec![1, 2, 3]).unwrap();
+        assert_eq!(server_messages.next().await, Some(Ok(vec![1, 2, 3])));
+
+        server.send(vec![4, 5]).unwrap();
+        assert_eq!(client_messages.next().await, Some(Ok(vec![4, 5])));
+    }
+
+    #[tokio::test]
+    async fn close_ends_messages() {
+        let (mut server, client) = connected_pair().await;
+
+        let mut server_messages = server.messages().unwrap();
+
+        drop(client);
+
+        assert_eq!(server_messages.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn connect_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        assert!(WebSocketConnector::new(&url).connect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn framed_loopback() {
+        let (a, b) = io::duplex(64);
+        let mut a = FramedTransport::new(a);
+        let mut b = FramedTransport::new(b);
+
+        let mut a_messages = a.messages().unwrap();
+        let mut b_messages = b.messages().unwrap();
+
+        a.send(vec![1, 2, 3]).unwrap();
+        a.send(vec![]).unwrap();
+        a.send(vec![7; 1000]).unwrap();
+        b.send(vec![4]).unwrap();
+
+        assert_eq!(b_messages.next().await, Some(Ok(vec![1, 2, 3])));
+        assert_eq!(b_messages.next().await, Some(Ok(vec![])));
+        assert_eq!(b_messages.next().await, Some(Ok(vec![7; 1000])));
+        assert_eq!(a_messages.next().await, Some(Ok(vec![4])));
+
+        drop(a);
+        assert_eq!(b_messages.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn framed_rejects_oversized_frame() {
+        let (mut raw, other) = io::duplex(64);
+        let mut transport = FramedTransport::new(other);
+        let mut messages = transport.messages().unwrap();
+
+        raw.write_all(&[0, 0, 0, 1, 9]).await.unwrap();
+        raw.write_all(&(MAX_FRAME_LENGTH as u32 + 1).to_be_bytes()).await.unwrap();
+
+        assert_eq!(messages.next().await, Some(Ok(vec![9])));
+        assert!(matches!(messages.next().await, Some(Err(TransportError::Failed(_)))));
+        assert_eq!(messages.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn framed_truncated_header_fails() {
+        let (mut raw, other) = io::duplex(64);
+        let mut transport = FramedTransport::new(other);
+        let mut messages = transport.messages().unwrap();
+
+        raw.write_all(&[0, 0, 0, 1, 9, 0, 0]).await.unwrap();
+        drop(raw);
+
+        assert_eq!(messages.next().await, Some(Ok(vec![9])));
+        assert!(matches!(messages.next().await, Some(Err(TransportError::Failed(_)))));
+        assert_eq!(messages.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn tcp() {
+        let mut acceptor = TcpAcceptor::bind("127.0.0.1:0").await.unwrap();
+        let mut transports = acceptor.transports().unwrap();
+
+        let mut client = tcp_connect(acceptor.local_addr()).await.unwrap();
+        let mut server = transports.next().await.unwrap();
+
+        let mut server_messages = server.messages().unwrap();
+
+        client.send(vec![1, 2]).unwrap();
+        assert_eq!(server_messages.next().await, Some(Ok(vec![1, 2])));
+
+        drop(client);
+        assert_eq!(server_messages.next().await, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix() {
+        let path = std::env::temp_dir().join(format!("omnistreams-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut acceptor = UnixAcceptor::bind(&path).unwrap();
+        let mut transports = acceptor.transports().unwrap();
+
+        let mut client = unix_connect(&path).await.unwrap();
+        let mut server = transports.next().await.unwrap();
+
+        let mut client_messages = client.messages().unwrap();
+
+        server.send(vec![3, 4]).unwrap();
+        assert_eq!(client_messages.next().await, Some(Ok(vec![3, 4])));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_pair() {
+        let (mut a, mut b) = pair();
+
+        let mut a_messages = a.messages().unwrap();
+        let mut b_messages = b.messages().unwrap();
+
+        a.send(vec![1]).unwrap();
+        b.send(vec![2]).unwrap();
+
+        assert_eq!(b_messages.next().await, Some(Ok(vec![1])));
+        assert_eq!(a_messages.next().await, Some(Ok(vec![2])));
+
+        a.send(vec![3]).unwrap();
+        a.close();
+        assert_eq!(a.send(vec![4]), Err(TransportError::Closed));
+
+        assert_eq!(b_messages.next().await, Some(Ok(vec![3])));
+        assert_eq!(b_messages.next().await, None);
+        assert_eq!(a_messages.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn memory_pair_drop_closes() {
+        let (a, mut b) = pair();
+
+        let mut b_messages = b.messages().unwrap();
+
+        drop(a);
+        assert_eq!(b_messages.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn memory_pair_capacity() {
+        let (mut a, mut b) = pair_with_capacity(2);
+
+        let mut b_messages = b.messages().unwrap();
+
+        assert_eq!(poll_fn(|cx| a.poll_ready(cx)).await, Ok(()));
+        a.send(vec![1]).unwrap();
+        assert_eq!(poll_fn(|cx| a.poll_ready(cx)).await, Ok(()));
+        a.send(vec![2]).unwrap();
+
+        // full until b catches up
+        assert!(poll_fn(|cx| a.poll_ready(cx)).now_or_never().is_none());
+        assert_eq!(a.send(vec![3]), Err(TransportError::Full));
+
+        assert_eq!(b_messages.next().await, Some(Ok(vec![1])));
+        assert_eq!(poll_fn(|cx| a.poll_ready(cx)).await, Ok(()));
+        a.send(vec![3]).unwrap();
+
+        drop(a);
+        assert_eq!(b_messages.next().await, Some(Ok(vec![2])));
+        assert_eq!(b_messages.next().await, Some(Ok(vec![3])));
+        assert_eq!(b_messages.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn framed_rejects_oversized_send() {
+        let (a, _b) = io::duplex(64);
+        let mut a = FramedTransport::new(a);
+
+        assert!(matches!(a.send(vec![0; MAX_FRAME_LENGTH + 1]), Err(TransportError::Failed(_))));
+        a.send(vec![1]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn multiplexer_over_tcp() {
+        let mut acceptor = TcpAcceptor::bind("127.0.0.1:0").await.unwrap();
+        let mut transports = acceptor.transports().unwrap();
+
+        let client = tcp_connect(acceptor.local_addr()).await.unwrap();
+        let server = transports.next().await.unwrap();
+
+        let mut server_mux = Multiplexer::new(server);
+        let mut client_mux = Multiplexer::new(client);
+
+        let mut server_events = server_mux.events().unwrap();
+
+        let consumer = client_mux.create_conduit(vec![]);
+
+        let mut producer = match server_events.next().await {
+            Some(MultiplexerEvent::Conduit(producer, _)) => producer,
+            _ => panic!("expected conduit"),
+        };
+        let mut producer_events = producer.event_stream().unwrap();
+
+        producer.request(1);
+        consumer.write(vec![5; 100_000]);
+
+        match producer_events.next().await {
+            Some(ProducerEvent::Data(data)) => assert_eq!(data, vec![5; 100_000]),
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn multiplexer_over_websocket() {
+        let (server, client) = connected_pair().await;
+
+        let mut server_mux = Multiplexer::new(server);
+        let mut client_mux = Multiplexer::new(client);
+
+        let mut server_events = server_mux.events().unwrap();
+
+        let mut consumer = client_mux.create_conduit(b"hi".to_vec());
+        let mut consumer_events = consumer.event_stream().unwrap();
+
+        let mut producer = match server_events.next().await {
+            Some(MultiplexerEvent::Conduit(producer, metadata)) => {
+                assert_eq!(metadata, b"hi".to_vec());
+                producer
+            },
+            _ => panic!("expected conduit"),
+        };
+        let mut producer_events = producer.event_stream().unwrap();
+
+        producer.request(1);
+        consumer_events.next().await.unwrap();
+
+        consumer.write(vec![42]);
+        consumer.end();
+
+        match producer_events.next().await {
+            Some(ProducerEvent::Data(data)) => assert_eq!(data, vec![42]),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        match producer_events.next().await {
+            Some(ProducerEvent::End) => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+}
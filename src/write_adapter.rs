@@ -1,9 +1,12 @@
-use tokio::io;
-use tokio::prelude::*;
-use futures::sync::mpsc;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncWrite};
+use futures::channel::mpsc;
+use futures::{ready, StreamExt};
 use super::{
     Consumer, ConsumerMessage, ConsumerEvent, ConsumerEventRx, ConsumerEventTx, ConsumerMessageRx,
-    ConsumerMessageTx
+    ConsumerMessageTx, CancelReason, Error,
 };
 
 
@@ -16,118 +19,170 @@ pub struct WriteAdapter
 
 #[derive(Debug)]
 enum WriteAdapterState<T, U>
-    where T: Future<Item=U, Error=io::Error>,
-          U: AsyncWrite,
+    where T: Future<Output=io::Result<U>>,
+          U: AsyncWrite + Unpin,
 {
-    WaitingForWriter(T),
+    WaitingForWriter(Pin<Box<T>>),
     Writing(U),
 }
 
 struct InnerTask<T, U>
-    where T: Future<Item=U, Error=io::Error>,
-          U: AsyncWrite,
+    where T: Future<Output=io::Result<U>>,
+          U: AsyncWrite + Unpin,
 {
     state: WriteAdapterState<T, U>,
     message_rx: ConsumerMessageRx<Vec<u8>>,
     event_tx: ConsumerEventTx,
     demand: usize,
+    // chunk currently being written, and how much of it is already out
+    chunk: Option<Vec<u8>>,
+    written: usize,
+    ending: bool,
 }
 
 impl<T, U> InnerTask<T, U>
-    where T: Future<Item=U, Error=io::Error>,
-          U: AsyncWrite,
+    where T: Future<Output=io::Result<U>>,
+          U: AsyncWrite + Unpin,
 {
     fn new(writer_future: T, message_rx: ConsumerMessageRx<Vec<u8>>, event_tx: ConsumerEventTx) -> InnerTask<T, U> {
 
         let initial_demand = 1;
 
-        (&event_tx).unbounded_send(ConsumerEvent::Request(initial_demand)).unwrap();
+        event_tx.unbounded_send(ConsumerEvent::Request(initial_demand)).unwrap();
 
         InnerTask {
-            state: WriteAdapterState::WaitingForWriter(writer_future),
+            state: WriteAdapterState::WaitingForWriter(Box::pin(writer_future)),
             message_rx,
             event_tx,
             demand: initial_demand,
+            chunk: None,
+            written: 0,
+            ending: false,
         }
     }
+
+    // Tell upstream to stop. Returning Ready drops the writer.
+    fn fail(&self, e: io::Error) -> Poll<()> {
+        let reason = CancelReason::Other(e.to_string());
+        // Upstream may already be gone, in which case there's nobody to tell
+        let _ = self.event_tx.unbounded_send(ConsumerEvent::Cancellation(reason));
+        Poll::Ready(())
+    }
 }
 
 impl<T, U> Future for InnerTask<T, U>
-    where T: Future<Item=U, Error=io::Error>,
-          U: AsyncWrite,
+    where T: Future<Output=io::Result<U>>,
+          U: AsyncWrite + Unpin,
 {
-    type Item = ();
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<(), io::Error> {
-
-        match self.state {
-            WriteAdapterState::Writing(ref mut writer) => {
-
-                // process any messages from upstream
-                loop {
-                    match self.message_rx.poll().unwrap() {
-                        Async::Ready(Some(ConsumerMessage::Write(data))) => {
-                            if self.demand == 0 {
-                                panic!("WriteAdapter: Attempt to write more than requested");
-                            }
-
-                            match writer.poll_write(&data) {
-                                Ok(Async::Ready(n)) => {
-                                    if n != data.len() {
-                                        panic!("WriteAdapter: Failed to write all data");
-                                    }
-                                    (&self.event_tx).unbounded_send(ConsumerEvent::Request(1)).unwrap();
-                                },
-                                Ok(Async::NotReady) => {
-                                },
-                                Err(_e) => {
-                                },
-                            }
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+
+        let this = self.get_mut();
+
+        if let WriteAdapterState::WaitingForWriter(ref mut fut) = this.state {
+            match fut.as_mut().poll(cx) {
+                Poll::Ready(Ok(writer)) => {
+                    this.state = WriteAdapterState::Writing(writer);
+                },
+                Poll::Ready(Err(e)) => {
+                    return this.fail(e);
+                },
+                Poll::Pending => {
+                    return Poll::Pending;
+                },
+            }
+        }
+
+        let writer = match this.state {
+            WriteAdapterState::Writing(ref mut writer) => writer,
+            WriteAdapterState::WaitingForWriter(_) => unreachable!(),
+        };
+
+        loop {
+            // Finish the current chunk before taking another one, picking
+            // up where a short or pending write left off.
+            if let Some(ref chunk) = this.chunk {
+                while this.written < chunk.len() {
+                    match Pin::new(&mut *writer).poll_write(cx, &chunk[this.written..]) {
+                        Poll::Ready(Ok(0)) => {
+                            return this.fail(io::ErrorKind::WriteZero.into());
                         },
-                        Async::Ready(Some(ConsumerMessage::End)) => {
-                            return Ok(Async::Ready(()));
+                        Poll::Ready(Ok(n)) => {
+                            this.written += n;
                         },
-                        Async::Ready(None) => {
-                            return Ok(Async::Ready(()));
+                        Poll::Ready(Err(e)) => {
+                            return this.fail(e);
                         },
-                        Async::NotReady => {
-                            break;
+                        Poll::Pending => {
+                            return Poll::Pending;
                         },
                     }
                 }
 
-                Ok(Async::NotReady)
-            },
-            WriteAdapterState::WaitingForWriter(ref mut fut) => {
-                return match fut.poll() {
-                    Ok(Async::Ready(writer)) => {
-                        self.state = WriteAdapterState::Writing(writer);
-                        task::current().notify();
-                        Ok(Async::NotReady)
-                    },
-                    Ok(Async::NotReady) => {
-                        Ok(Async::NotReady)
-                    },
-                    Err(e) => {
-                        Err(e)
-                    }
+                this.chunk = None;
+                this.written = 0;
+                this.demand += 1;
+                // Upstream may already be gone, the End will still come
+                let _ = this.event_tx.unbounded_send(ConsumerEvent::Request(1));
+            }
+
+            if this.ending {
+                if let Err(e) = ready!(Pin::new(&mut *writer).poll_flush(cx)) {
+                    return this.fail(e);
+                }
+
+                if let Err(e) = ready!(Pin::new(&mut *writer).poll_shutdown(cx)) {
+                    return this.fail(e);
                 }
-            },
+
+                return Poll::Ready(());
+            }
+
+            match this.message_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ConsumerMessage::Write(data))) => {
+                    if this.demand == 0 {
+                        panic!("WriteAdapter: Attempt to write more than requested");
+                    }
+
+                    this.demand -= 1;
+                    this.chunk = Some(data);
+                },
+                Poll::Ready(Some(ConsumerMessage::End)) => {
+                    this.ending = true;
+                },
+                // Leave the output as it is rather than shutting it down as
+                // if it were complete. A consumer dropped without ending
+                // didn't finish either.
+                Poll::Ready(Some(ConsumerMessage::Abort(_))) | Poll::Ready(None) => {
+                    return Poll::Ready(());
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
+
+        // Nothing more to write for now, so don't leave data sitting in the
+        // writer's buffers.
+        if let Poll::Ready(Err(e)) = Pin::new(&mut *writer).poll_flush(cx) {
+            return this.fail(e);
         }
+
+        Poll::Pending
     }
 }
 
 impl WriteAdapter {
     pub fn new<T, U>(writer_future: T) -> WriteAdapter
-        where T: Future<Item=U, Error=io::Error> + Send + 'static,
-              U: AsyncWrite + Send + 'static,
+        where T: Future<Output=io::Result<U>> + Send + 'static,
+              U: AsyncWrite + Unpin + Send + 'static,
     {
         let (message_tx, message_rx) = mpsc::unbounded::<ConsumerMessage<Vec<u8>>>();
         let (event_tx, event_rx) = mpsc::unbounded::<ConsumerEvent>();
 
         let inner_task = InnerTask::new(writer_future, message_rx, event_tx);
-        tokio::spawn(inner_task.map_err(|_| {}));
+        tokio::spawn(inner_task);
 
         WriteAdapter {
             message_tx,
@@ -138,13 +193,18 @@ impl WriteAdapter {
 
 impl Consumer<Vec<u8>> for WriteAdapter {
     fn write(&self, data: Vec<u8>) {
-        let tx = &self.message_tx;
-        tx.unbounded_send(ConsumerMessage::Write(data)).unwrap();
+        // Writer has failed and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::Write(data));
     }
 
     fn end(&self) {
-        let tx = &self.message_tx;
-        tx.unbounded_send(ConsumerMessage::End).unwrap();
+        // Writer has failed and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::End);
+    }
+
+    fn abort(&self, error: Error) {
+        // Writer has failed and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ConsumerMessage::Abort(error));
     }
 
     fn event_stream(&mut self) -> Option<ConsumerEventRx> {
@@ -155,3 +215,164 @@ impl Consumer<Vec<u8>> for WriteAdapter {
         self.event_rx = Some(event_stream);
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Takes at most 3 bytes per write and isn't ready every other time.
+    // Fails once `limit` bytes have been written.
+    #[derive(Default)]
+    struct SlowWriter {
+        data: Arc<Mutex<Vec<u8>>>,
+        shut_down: Arc<Mutex<bool>>,
+        limit: Option<usize>,
+        not_ready: bool,
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.not_ready = !self.not_ready;
+
+            if self.not_ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            let mut data = self.data.lock().unwrap();
+
+            if self.limit.is_some_and(|limit| data.len() >= limit) {
+                return Poll::Ready(Err(io::Error::other("disk full")));
+            }
+
+            let n = buf.len().min(3);
+            data.extend(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            *self.shut_down.lock().unwrap() = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Writes the chunks as they're requested, ends, and returns every event
+    // after the requests.
+    async fn write_all(writer: SlowWriter, chunks: Vec<Vec<u8>>) -> Vec<ConsumerEvent> {
+        let mut consumer = WriteAdapter::new(futures::future::ok(writer));
+        let mut events = consumer.event_stream().unwrap();
+        let mut chunks = chunks.into_iter();
+
+        while let Some(event) = events.next().await {
+            match event {
+                ConsumerEvent::Request(num_items) => {
+                    for _ in 0..num_items {
+                        match chunks.next() {
+                            Some(chunk) => consumer.write(chunk),
+                            None => {
+                                consumer.end();
+                                break;
+                            },
+                        }
+                    }
+                },
+                ConsumerEvent::Cancellation(_) => {
+                    let mut rest = vec![event];
+                    rest.extend(events.collect::<Vec<_>>().await);
+                    return rest;
+                },
+            }
+        }
+
+        Vec::new()
+    }
+
+    #[tokio::test]
+    async fn partial_and_pending_writes() {
+        let writer = SlowWriter::default();
+        let data = writer.data.clone();
+        let shut_down = writer.shut_down.clone();
+
+        let chunks = vec![vec![1, 2, 3, 4, 5], vec![6], vec![], vec![7, 8, 9, 10, 11, 12, 13]];
+
+        assert_eq!(write_all(writer, chunks).await, Vec::new());
+        assert_eq!(*data.lock().unwrap(), (1..=13).collect::<Vec<u8>>());
+        assert!(*shut_down.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn write_error_cancels() {
+        let writer = SlowWriter {
+            limit: Some(4),
+            ..Default::default()
+        };
+        let shut_down = writer.shut_down.clone();
+
+        let events = write_all(writer, vec![vec![1; 10], vec![2; 10]]).await;
+
+        assert_eq!(events, vec![ConsumerEvent::Cancellation(CancelReason::Other("disk full".to_string()))]);
+        assert!(!*shut_down.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn abort_skips_shutdown() {
+        let writer = SlowWriter::default();
+        let data = writer.data.clone();
+        let shut_down = writer.shut_down.clone();
+
+        let mut consumer = WriteAdapter::new(futures::future::ok(writer));
+        let mut events = consumer.event_stream().unwrap();
+
+        assert_eq!(events.next().await, Some(ConsumerEvent::Request(1)));
+        consumer.write(vec![1, 2, 3, 4]);
+        consumer.abort(Error::Disconnected);
+
+        assert_eq!(events.next().await, Some(ConsumerEvent::Request(1)));
+        assert_eq!(events.next().await, None);
+        assert_eq!(*data.lock().unwrap(), vec![1, 2, 3, 4]);
+        assert!(!*shut_down.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn drop_skips_shutdown() {
+        let writer = SlowWriter::default();
+        let data = writer.data.clone();
+        let shut_down = writer.shut_down.clone();
+
+        let mut consumer = WriteAdapter::new(futures::future::ok(writer));
+        let mut events = consumer.event_stream().unwrap();
+
+        assert_eq!(events.next().await, Some(ConsumerEvent::Request(1)));
+        consumer.write(vec![1, 2, 3, 4]);
+        drop(consumer);
+
+        assert_eq!(events.next().await, Some(ConsumerEvent::Request(1)));
+        assert_eq!(events.next().await, None);
+        assert_eq!(*data.lock().unwrap(), vec![1, 2, 3, 4]);
+        assert!(!*shut_down.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn open_error_cancels() {
+        let mut consumer = WriteAdapter::new(tokio::fs::File::create("/nonexistent/omnistreams"));
+        let mut events = consumer.event_stream().unwrap();
+
+        assert_eq!(events.next().await, Some(ConsumerEvent::Request(1)));
+
+        match events.next().await {
+            Some(ConsumerEvent::Cancellation(CancelReason::Other(_))) => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // writes after the failure are ignored
+        consumer.write(vec![1]);
+        consumer.end();
+        assert_eq!(events.next().await, None);
+    }
+}
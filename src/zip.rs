@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use super::{
+    Producer, ProducerEvent, ProducerEventRx, ProducerEventTx,
+    ProducerMessage, ProducerMessageRx, ProducerMessageTx,
+    Streamer, CancelReason, Error,
+};
+
+
+/// Pairs up the items of two producers. Each side is requested exactly what
+/// was requested downstream, so neither can get further ahead than that.
+/// Ends as soon as either side does, cancelling the other.
+#[derive(Debug)]
+pub struct Zip<A, B> {
+    message_tx: ProducerMessageTx,
+    event_rx: Option<ProducerEventRx<(A, B)>>,
+}
+
+struct Side<T, P> {
+    producer: P,
+    event_rx: ProducerEventRx<T>,
+    // requested but not received yet
+    in_flight: usize,
+    buffer: VecDeque<T>,
+    ended: bool,
+}
+
+struct InnerTask<A, B, PA, PB> {
+    message_rx: ProducerMessageRx,
+    event_tx: ProducerEventTx<(A, B)>,
+    left: Side<A, PA>,
+    right: Side<B, PB>,
+    demand: usize,
+}
+
+const OTHER_SIDE_ENDED: &str = "other side of zip ended";
+
+impl<A, B> Zip<A, B>
+    where A: Send + 'static,
+          B: Send + 'static,
+{
+    pub fn new<PA, PB>(left: PA, right: PB) -> Zip<A, B>
+        where PA: Producer<A> + Send + 'static,
+              PB: Producer<B> + Send + 'static,
+    {
+        let (message_tx, message_rx) = mpsc::unbounded::<ProducerMessage>();
+        let (event_tx, event_rx) = mpsc::unbounded::<ProducerEvent<(A, B)>>();
+
+        let inner_task = InnerTask {
+            message_rx,
+            event_tx,
+            left: Side::new(left),
+            right: Side::new(right),
+            demand: 0,
+        };
+        tokio::spawn(inner_task);
+
+        Zip {
+            message_tx,
+            event_rx: Some(event_rx),
+        }
+    }
+}
+
+impl<A, B> Streamer for Zip<A, B> {
+    fn cancel(&mut self, reason: CancelReason) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Cancel(reason));
+    }
+}
+
+impl<A, B> Producer<(A, B)> for Zip<A, B>
+    where A: Send + 'static,
+          B: Send + 'static,
+{
+    fn request(&mut self, num_items: usize) {
+        // Already ended and channel dropped, so just ignore
+        let _ = self.message_tx.unbounded_send(ProducerMessage::Request(num_items));
+    }
+
+    fn event_stream(&mut self) -> Option<ProducerEventRx<(A, B)>> {
+        Option::take(&mut self.event_rx)
+    }
+
+    fn set_event_stream(&mut self, event_stream: ProducerEventRx<(A, B)>) {
+        self.event_rx = Some(event_stream);
+    }
+}
+
+impl<T, P> Side<T, P>
+    where T: Send + 'static,
+          P: Producer<T>,
+{
+    fn new(mut producer: P) -> Side<T, P> {
+        let event_rx = producer.event_stream().expect("no event stream");
+
+        Side {
+            producer,
+            event_rx,
+            in_flight: 0,
+            buffer: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    fn poll_events(&mut self, cx: &mut Context) -> Result<(), Error> {
+        while !self.ended {
+            match self.event_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ProducerEvent::Data(item))) => {
+                    self.in_flight = self.in_flight.saturating_sub(1);
+                    self.buffer.push_back(item);
+                },
+                Poll::Ready(Some(ProducerEvent::End)) => {
+                    self.ended = true;
+                },
+                Poll::Ready(Some(ProducerEvent::Error(e))) => {
+                    self.ended = true;
+                    return Err(e);
+                },
+                Poll::Ready(None) => {
+                    self.ended = true;
+                    return Err(Error::Disconnected);
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    // Nothing left to pair up with
+    fn exhausted(&self) -> bool {
+        self.ended && self.buffer.is_empty()
+    }
+
+    fn request_up_to(&mut self, demand: usize) {
+        let outstanding = self.in_flight + self.buffer.len();
+
+        if !self.ended && demand > outstanding {
+            self.producer.request(demand - outstanding);
+            self.in_flight += demand - outstanding;
+        }
+    }
+
+    fn cancel(&mut self, reason: CancelReason) {
+        if !self.ended {
+            self.ended = true;
+            self.producer.cancel(reason);
+        }
+    }
+}
+
+impl<A, B, PA, PB> InnerTask<A, B, PA, PB>
+    where A: Send + 'static,
+          B: Send + 'static,
+          PA: Producer<A>,
+          PB: Producer<B>,
+{
+    fn cancel_both(&mut self, reason: CancelReason) {
+        self.left.cancel(reason.clone());
+        self.right.cancel(reason);
+    }
+
+    fn fail(&mut self, e: Error) -> Poll<()> {
+        self.cancel_both(CancelReason::Other(e.to_string()));
+        // Consumer may already be gone, in which case there's nobody to tell
+        let _ = self.event_tx.unbounded_send(ProducerEvent::Error(e));
+        Poll::Ready(())
+    }
+}
+
+// The producers and channels are never pinned.
+impl<A, B, PA, PB> Unpin for InnerTask<A, B, PA, PB> {}
+
+impl<A, B, PA, PB> Future for InnerTask<A, B, PA, PB>
+    where A: Send + 'static,
+          B: Send + 'static,
+          PA: Producer<A>,
+          PB: Producer<B>,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+
+        let this = self.get_mut();
+
+        loop {
+            match this.message_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(ProducerMessage::Request(num_items))) => {
+                    this.demand += num_items;
+                },
+                Poll::Ready(Some(ProducerMessage::Cancel(reason))) => {
+                    this.cancel_both(reason);
+                    return Poll::Ready(());
+                },
+                Poll::Ready(None) => {
+                    this.cancel_both(CancelReason::Disconnected);
+                    return Poll::Ready(());
+                },
+                Poll::Pending => {
+                    break;
+                },
+            }
+        }
+
+        if let Err(e) = this.left.poll_events(cx) {
+            return this.fail(e);
+        }
+
+        if let Err(e) = this.right.poll_events(cx) {
+            return this.fail(e);
+        }
+
+        while this.demand > 0 && !this.left.buffer.is_empty() && !this.right.buffer.is_empty() {
+            let left = this.left.buffer.pop_front().unwrap();
+            let right = this.right.buffer.pop_front().unwrap();
+            this.demand -= 1;
+
+            if this.event_tx.unbounded_send(ProducerEvent::Data((left, right))).is_err() {
+                // nobody is listening anymore
+                this.cancel_both(CancelReason::Disconnected);
+                return Poll::Ready(());
+            }
+        }
+
+        if this.left.exhausted() || this.right.exhausted() {
+            this.cancel_both(CancelReason::Other(OTHER_SIDE_ENDED.to_string()));
+            let _ = this.event_tx.unbounded_send(ProducerEvent::End);
+            return Poll::Ready(());
+        }
+
+        this.left.request_up_to(this.demand);
+        this.right.request_up_to(this.demand);
+
+        Poll::Pending
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use futures::stream;
+    use crate::{MapConduit, RangeProducer, StreamProducer, Conduit, Consumer, ConsumerEvent};
+
+    #[tokio::test]
+    async fn pairs() {
+        let names = StreamProducer::new(stream::iter(vec!["a", "b", "c"]));
+        let zip = Zip::new(RangeProducer::new(0, None), names);
+
+        let items: Vec<(i64, &str)> = zip.into_stream(2).collect().await;
+        assert_eq!(items, vec![(0, "a"), (1, "b"), (2, "c")]);
+    }
+
+    #[tokio::test]
+    async fn requests_in_lockstep() {
+        let (mut left, left_out) = MapConduit::new(|x: i32| x).split();
+        let (mut right, right_out) = MapConduit::new(|x: i32| x).split();
+        let mut left_events = left.event_stream().unwrap();
+        let mut right_events = right.event_stream().unwrap();
+
+        let mut zip = Zip::new(left_out, right_out);
+        let mut events = zip.event_stream().unwrap();
+
+        zip.request(3);
+        assert_eq!(left_events.next().await, Some(ConsumerEvent::Request(3)));
+        assert_eq!(right_events.next().await, Some(ConsumerEvent::Request(3)));
+
+        left.write(1);
+        left.write(2);
+        right.write(10);
+
+        match events.next().await {
+            Some(ProducerEvent::Data(pair)) => assert_eq!(pair, (1, 10)),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // both sides are topped back up to what was requested
+        zip.request(1);
+        assert_eq!(left_events.next().await, Some(ConsumerEvent::Request(1)));
+        assert_eq!(right_events.next().await, Some(ConsumerEvent::Request(1)));
+    }
+
+    #[tokio::test]
+    async fn end_cancels_other_side() {
+        let (left, left_out) = MapConduit::new(|x: i32| x).split();
+        let (mut right, right_out) = MapConduit::new(|x: i32| x).split();
+        let mut right_events = right.event_stream().unwrap();
+
+        let zip = Zip::new(left_out, right_out);
+        left.write(1);
+        left.end();
+        right.write(10);
+        right.write(20);
+
+        let items: Vec<(i32, i32)> = zip.into_stream(5).collect().await;
+        assert_eq!(items, vec![(1, 10)]);
+
+        loop {
+            match right_events.next().await {
+                Some(ConsumerEvent::Cancellation(CancelReason::Other(reason))) => {
+                    assert_eq!(reason, OTHER_SIDE_ENDED);
+                    break;
+                },
+                Some(ConsumerEvent::Request(_)) => (),
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn error_fails_zip() {
+        let (left, left_out) = MapConduit::new(|x: i32| x).split();
+
+        let zip = Zip::new(left_out, RangeProducer::new(0, None));
+        left.abort(Error::Disconnected);
+
+        let mut items = zip.into_stream(1);
+        assert!(items.next().await.is_none());
+        assert!(matches!(items.take_error(), Some(Error::Disconnected)));
+    }
+}